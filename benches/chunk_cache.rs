@@ -0,0 +1,59 @@
+// Compare the throughput of concurrent gets through the chunk cache with different shard counts.
+//
+// Run with `cargo bench --bench chunk_cache`, the gets of each thread count are timed
+// with a single locked chunk list and with the default shards.
+
+use cykv::{CyStore, EvictionPolicyKind, KvEngine, LruCacheManager, DEFAULT_CHUNK_SHARDS};
+use std::thread;
+use std::time::Instant;
+use tempfile::TempDir;
+
+const KEYS: usize = 10_000;
+const GETS_PER_THREAD: usize = 100_000;
+const CACHE_BYTES: u64 = 64 << 20;
+
+fn run(shards: usize, threads: usize) -> f64 {
+    let dir = TempDir::new().unwrap();
+    let cache_manager = LruCacheManager::with_shards(CACHE_BYTES, EvictionPolicyKind::Lru, shards);
+    let store = CyStore::open(dir.path().to_path_buf(), Box::new(cache_manager)).unwrap();
+    for i in 0..KEYS {
+        store
+            .set(format!("key{:05}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..GETS_PER_THREAD {
+                    let key_id = (i * 7919 + thread_id * 104_729) % KEYS;
+                    store.get(format!("key{:05}", key_id)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    (threads * GETS_PER_THREAD) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>16} {:>16}",
+        "threads",
+        "1 shard",
+        format!("{} shards", DEFAULT_CHUNK_SHARDS)
+    );
+    for threads in [1, 2, 4, 8, 16] {
+        println!(
+            "{:>8} {:>12.0} op/s {:>12.0} op/s",
+            threads,
+            run(1, threads),
+            run(DEFAULT_CHUNK_SHARDS, threads)
+        );
+    }
+}
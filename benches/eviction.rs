@@ -0,0 +1,121 @@
+// Compare the hit ratios of the chunk cache eviction policies.
+//
+// Run with `cargo bench --bench eviction`, the synthetic traces are always replayed,
+// set CYKV_TRACE to a file with one accessed key per line to replay a recorded trace too.
+
+use cykv::{new_policy, EvictionPolicyKind};
+use std::env;
+use std::fs;
+
+const CAPACITY: usize = 1000;
+const POLICIES: [EvictionPolicyKind; 4] = [
+    EvictionPolicyKind::Lru,
+    EvictionPolicyKind::Clock,
+    EvictionPolicyKind::SegmentedLru,
+    EvictionPolicyKind::TinyLfu,
+];
+
+// xorshift64*, deterministic so the runs are comparable
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Keys drawn from a Zipf distribution over n keys
+fn zipf(rng: &mut Rng, n: usize, s: f64, len: usize) -> Vec<u64> {
+    let mut cdf = Vec::with_capacity(n);
+    let mut sum = 0.0;
+    for rank in 1..=n {
+        sum += 1.0 / (rank as f64).powf(s);
+        cdf.push(sum);
+    }
+
+    (0..len)
+        .map(|_| {
+            let x = rng.next_f64() * sum;
+            cdf.partition_point(|&p| p < x) as u64
+        })
+        .collect()
+}
+
+fn traces() -> Vec<(String, Vec<u64>)> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut traces = Vec::new();
+
+    traces.push((
+        "zipf".to_owned(),
+        zipf(&mut rng, 10 * CAPACITY, 0.99, 200_000),
+    ));
+
+    // The popular keys interleaved with long scans of cold keys
+    let mut scans = Vec::new();
+    let mut cold = 1 << 32;
+    for part in zipf(&mut rng, 10 * CAPACITY, 0.99, 200_000).chunks(20_000) {
+        scans.extend_from_slice(part);
+        scans.extend(cold..cold + 5 * CAPACITY as u64);
+        cold += 5 * CAPACITY as u64;
+    }
+    traces.push(("zipf+scan".to_owned(), scans));
+
+    // A loop slightly larger than the cache
+    let loop_len = (CAPACITY + CAPACITY / 5) as u64;
+    traces.push((
+        "loop".to_owned(),
+        (0..200_000).map(|i| i % loop_len).collect(),
+    ));
+
+    if let Ok(path) = env::var("CYKV_TRACE") {
+        let recorded = fs::read_to_string(&path).expect("fail to read the trace");
+        let mut keys = std::collections::HashMap::new();
+        let trace = recorded
+            .lines()
+            .map(|line| {
+                let next = keys.len() as u64;
+                *keys.entry(line.trim().to_owned()).or_insert(next)
+            })
+            .collect();
+        traces.push((path, trace));
+    }
+
+    traces
+}
+
+fn hit_ratio(policy: EvictionPolicyKind, trace: &[u64]) -> f64 {
+    let mut cache = new_policy::<u64, ()>(policy, CAPACITY);
+    let mut hits = 0;
+    for key in trace {
+        if cache.get(key).is_some() {
+            hits += 1;
+        } else {
+            cache.put(*key, ());
+        }
+    }
+
+    hits as f64 / trace.len() as f64
+}
+
+fn main() {
+    print!("{:<16}", "trace");
+    for policy in POLICIES.iter() {
+        print!("{:>14}", format!("{:?}", policy));
+    }
+    println!();
+
+    for (name, trace) in traces() {
+        print!("{:<16}", name);
+        for policy in POLICIES.iter() {
+            print!("{:>13.2}%", hit_ratio(*policy, &trace) * 100.0);
+        }
+        println!();
+    }
+}
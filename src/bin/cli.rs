@@ -0,0 +1,374 @@
+use cykv::{Client, CyKvError, KvEngine, Result, ScanOptions, WireError};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::io::{self, BufRead, IsTerminal};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::path::PathBuf;
+use std::process;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:2958";
+const HISTORY_FILE: &str = ".cykv_history";
+
+const USAGE: &str = "usage: cykv-cli [-a ADDR] [--json] [COMMAND [ARGS...]]
+
+Runs COMMAND and exits, reads the commands from stdin if it isn't a terminal,
+or else starts an interactive shell.
+
+options:
+    -a, --addr ADDR    the server address, 127.0.0.1:2958 by default
+    --json             print the results as JSON
+    -h, --help         print this help";
+
+const COMMANDS: &str = "commands:
+    get KEY                       print the value of KEY
+    set KEY VALUE                 set KEY to VALUE, print the previous value
+    rm KEY                        remove KEY, print the removed value
+    scan [START [END]] [OPTIONS]  print the pairs in [START, END), - for no bound
+    prefix PREFIX [OPTIONS]       print the pairs of the keys starting with PREFIX
+    keys [START [END]] [OPTIONS]  print the keys in [START, END)
+    count [START [END]]           print the number of the keys in [START, END)
+    delrange START END            remove the keys in [START, END)
+    stats                         print the statistics of the store
+    sync                          persist the data buffered by the server
+    resize BYTES                  change the memory budget of the chunk cache
+    help                          print this help
+    quit                          exit the shell
+scan options:
+    --limit N                     stop after N pairs
+    --reverse                     scan from the end";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Pretty,
+    Json,
+}
+
+fn main() {
+    let mut addr = DEFAULT_ADDR.to_owned();
+    let mut output = Output::Pretty;
+    let mut args = env::args().skip(1);
+    let mut command = Vec::new();
+
+    while let Some(arg) = args.next() {
+        // The arguments after the command belong to it
+        if !command.is_empty() {
+            command.push(arg);
+            continue;
+        }
+        match arg.as_str() {
+            "-a" | "--addr" => match args.next() {
+                Some(value) => addr = value,
+                None => exit_with_usage("missing the address"),
+            },
+            "--json" => output = Output::Json,
+            "-h" | "--help" => {
+                println!("{}\n\n{}", USAGE, COMMANDS);
+                return;
+            }
+            _ if arg.starts_with('-') => exit_with_usage(&format!("unknown option {}", arg)),
+            _ => command.push(arg),
+        }
+    }
+
+    let client = match Client::connect(addr.as_str()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("could not connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    let ok = if !command.is_empty() {
+        run(&client, &command, output)
+    } else if io::stdin().is_terminal() {
+        shell(&client, &addr, output)
+    } else {
+        batch(&client, output)
+    };
+    if !ok {
+        process::exit(1);
+    }
+}
+
+fn exit_with_usage(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+// The interactive shell, the history is kept in ~/.cykv_history
+fn shell(client: &Client, addr: &str, output: Output) -> bool {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("could not start the shell: {}", e);
+            return false;
+        }
+    };
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // The file doesn't exist the first time
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = format!("{}> ", addr);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C drops the line being edited
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        match split_line(&line) {
+            Ok(args) if matches!(args[0].as_str(), "quit" | "exit") => break,
+            Ok(args) => {
+                run(client, &args, output);
+            }
+            Err(e) => print_error(&e, output),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("could not save the history: {}", e);
+        }
+    }
+    true
+}
+
+// Run the commands read from stdin, one per line, and keep going after a failed one,
+// the blank lines and the lines starting with # are skipped
+fn batch(client: &Client, output: Output) -> bool {
+    let mut ok = true;
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match split_line(line) {
+            Ok(args) => ok &= run(client, &args, output),
+            Err(e) => {
+                print_error(&e, output);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+// Run a command and print its result, or its error
+fn run(client: &Client, args: &[String], output: Output) -> bool {
+    match execute(client, args, output) {
+        Ok(()) => true,
+        Err(e) => {
+            print_error(&e, output);
+            false
+        }
+    }
+}
+
+fn execute(client: &Client, args: &[String], output: Output) -> Result<()> {
+    let (name, args) = args.split_first().unwrap();
+    // The values of the other commands may start with --
+    let (args, options) = match name.as_str() {
+        "scan" | "prefix" | "keys" => scan_options(args)?,
+        _ => (args.to_vec(), ScanOptions::default()),
+    };
+
+    match (name.as_str(), args.as_slice()) {
+        ("get", [key]) => print_value(client.get(key.clone())?, output),
+        ("set", [key, value]) => print_value(client.set(key.clone(), value.clone())?, output),
+        ("rm", [key]) => print_value(Some(client.remove(key.clone())?), output),
+        ("scan", bounds) if bounds.len() <= 2 => {
+            print_pairs(client.range(bounds_of(bounds), options), output)?;
+        }
+        ("prefix", [prefix]) => print_pairs(client.scan_prefix(prefix.clone(), options), output)?,
+        ("keys", bounds) if bounds.len() <= 2 => {
+            let keys = client.keys(bounds_of(bounds), options)?;
+            match output {
+                Output::Pretty => {
+                    for (i, key) in keys.iter().enumerate() {
+                        println!("{}) {:?}", i + 1, key);
+                    }
+                }
+                Output::Json => println!("{}", serde_json::to_string(&keys)?),
+            }
+        }
+        ("count", bounds) if bounds.len() <= 2 => {
+            println!("{}", client.count(bounds_of(bounds))?);
+        }
+        ("delrange", bounds) if bounds.len() == 2 => {
+            println!("{}", client.delete_range(bounds_of(bounds))?);
+        }
+        ("stats", []) => {
+            let stats = client.stats()?;
+            match output {
+                Output::Pretty => println!("{}", serde_json::to_string_pretty(&stats)?),
+                Output::Json => println!("{}", serde_json::to_string(&stats)?),
+            }
+        }
+        ("sync", []) => {
+            client.sync()?;
+            print_done(output);
+        }
+        ("resize", [bytes]) => {
+            let bytes = bytes
+                .parse()
+                .map_err(|_| CyKvError::InvalidArgument(format!("invalid size {}", bytes)))?;
+            client.resize_cache(bytes)?;
+            print_done(output);
+        }
+        ("help", []) => println!("{}", COMMANDS),
+        _ => {
+            return Err(CyKvError::InvalidArgument(format!(
+                "invalid command {}, try help",
+                name
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+// Take the scan options out of the arguments
+fn scan_options(args: &[String]) -> Result<(Vec<String>, ScanOptions)> {
+    let mut rest = Vec::new();
+    let mut options = ScanOptions::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--reverse" => options.reverse = true,
+            "--limit" => {
+                let limit = args
+                    .next()
+                    .and_then(|limit| limit.parse().ok())
+                    .ok_or_else(|| CyKvError::InvalidArgument("invalid --limit".to_owned()))?;
+                options.limit = Some(limit);
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((rest, options))
+}
+
+// The half-open range [START, END), - stands for no bound
+fn bounds_of(bounds: &[String]) -> (Bound<String>, Bound<String>) {
+    let bound = |i: usize| bounds.get(i).filter(|bound| bound.as_str() != "-");
+    (
+        bound(0).cloned().map_or(Unbounded, Included),
+        bound(1).cloned().map_or(Unbounded, Excluded),
+    )
+}
+
+fn print_value(value: Option<String>, output: Output) {
+    match (output, value) {
+        (Output::Pretty, Some(value)) => println!("{:?}", value),
+        (Output::Pretty, None) => println!("(nil)"),
+        (Output::Json, value) => println!("{}", serde_json::to_string(&value).unwrap()),
+    }
+}
+
+fn print_done(output: Output) {
+    match output {
+        Output::Pretty => println!("OK"),
+        Output::Json => println!("null"),
+    }
+}
+
+// The pretty output is printed as the pages arrive,
+// the JSON output is one array
+fn print_pairs(
+    pairs: impl Iterator<Item = Result<(String, String)>>,
+    output: Output,
+) -> Result<()> {
+    match output {
+        Output::Pretty => {
+            for (i, pair) in pairs.enumerate() {
+                let (key, value) = pair?;
+                println!("{}) {:?} => {:?}", i + 1, key, value);
+            }
+        }
+        Output::Json => {
+            let pairs = pairs.collect::<Result<Vec<_>>>()?;
+            println!("{}", serde_json::to_string(&pairs)?);
+        }
+    }
+    Ok(())
+}
+
+fn print_error(err: &CyKvError, output: Output) {
+    match output {
+        Output::Pretty => eprintln!("(error) {}", err),
+        Output::Json => println!("{}", serde_json::json!({ "Err": WireError::from(err) })),
+    }
+}
+
+// Split a line into words, a word with spaces can be quoted with " or ',
+// and \ escapes the next character in a double-quoted word
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut word = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => break,
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => return Err(unterminated()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(unterminated()),
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(unterminated()),
+                    }
+                },
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+
+    if words.is_empty() {
+        return Err(CyKvError::InvalidArgument("empty command".to_owned()));
+    }
+    Ok(words)
+}
+
+fn unterminated() -> CyKvError {
+    CyKvError::InvalidArgument("unterminated quote".to_owned())
+}
@@ -1,13 +1,467 @@
-use cykv::NoCacheManager;
-use std::env::current_dir;
-use std::net::{IpAddr, SocketAddr};
-use std::str::FromStr;
+use cykv::*;
+use log::{error, info, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
 
-const SERVER_PORT: u16 = 2958;
+const USAGE: &str = "usage: server [--config FILE] [OPTIONS]
+
+The options override the settings of the config file, a JSON object
+with the same names, like {\"addr\": \"0.0.0.0:2958\", \"cache\": \"lru\"}.
+
+options:
+    --config FILE              read the settings from FILE
+    --addr ADDR                the listen address, 127.0.0.1:2958 by default
+    --resp-addr ADDR           also serve the Redis clients on ADDR, disabled by default
+    --data-dir DIR             the directory of the logs, created if missing, . by default
+    --cache TYPE               none, lru or mmap, none by default
+    --cache-bytes BYTES        the memory budget of the lru cache, 64M by default
+    --cache-policy POLICY      lru, clock, slru or tinylfu, lru by default
+    --chunk-size BYTES         the chunk size of the lru cache, 4K by default
+    --write-back               buffer the writes in the lru cache
+    --flush-interval-ms MS     flush the write-back cache every MS milliseconds
+    --value-cache-bytes BYTES  the memory budget of the value cache, 0 (disabled) by default
+    --durability MODE          buffered, sync or periodic, buffered by default
+    --sync-interval-ms MS      sync the writes every MS milliseconds, 1000 by default
+    --compact-threshold BYTES  compact the logs when this many bytes are stale, 32M by default
+    --workers N                the threads serving the connections, 64 by default
+    --max-connections N        the connections served or waiting for a worker, 1024 by default
+    --idle-timeout-ms MS       close the connections idle this long, 300000 by default
+    --read-timeout-ms MS       the time to read a request and write a response, 30000 by default
+    --queue-timeout-ms MS      the time a connection waits for a free worker, 10000 by default
+    --shutdown-timeout-ms MS   the time to finish the requests in flight at shutdown, 10000 by default
+    --log-level LEVEL          off, error, warn, info, debug or trace, info by default
+    -h, --help                 print this help
+
+BYTES takes a K, M or G suffix, an idle, read or queue timeout of 0 waits forever.
+SIGINT or SIGTERM shuts the server down, a second one exits at once.";
+
+const DEFAULT_CACHE_BYTES: u64 = 64 << 20;
+const DEFAULT_SYNC_INTERVAL_MS: u64 = 1000;
+
+// The settings as written, checked by validate()
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    addr: String,
+    resp_addr: String, // empty to disable the RESP listener
+    data_dir: PathBuf,
+    cache: String,
+    cache_bytes: Option<u64>,
+    cache_policy: Option<String>,
+    chunk_size: Option<usize>,
+    write_back: bool,
+    flush_interval_ms: Option<u64>,
+    value_cache_bytes: u64,
+    durability: String,
+    sync_interval_ms: Option<u64>,
+    compact_threshold: u64,
+    workers: usize,
+    max_connections: usize,
+    idle_timeout_ms: u64,
+    read_timeout_ms: u64,
+    queue_timeout_ms: u64,
+    shutdown_timeout_ms: u64,
+    log_level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:2958".to_owned(),
+            resp_addr: String::new(),
+            data_dir: PathBuf::from("."),
+            cache: "none".to_owned(),
+            cache_bytes: None,
+            cache_policy: None,
+            chunk_size: None,
+            write_back: false,
+            flush_interval_ms: None,
+            value_cache_bytes: 0,
+            durability: "buffered".to_owned(),
+            sync_interval_ms: None,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+            workers: DEFAULT_WORKERS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout_ms: DEFAULT_IDLE_TIMEOUT.as_millis() as u64,
+            read_timeout_ms: DEFAULT_READ_TIMEOUT.as_millis() as u64,
+            queue_timeout_ms: DEFAULT_QUEUE_TIMEOUT.as_millis() as u64,
+            shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT.as_millis() as u64,
+            log_level: "info".to_owned(),
+        }
+    }
+}
+
+// The validated settings
+struct Settings {
+    addr: SocketAddr,
+    data_dir: PathBuf,
+    cache_manager: Box<dyn CacheManager>,
+    store: StoreOptions,
+    server: ServerOptions,
+    log_level: LevelFilter,
+}
 
 fn main() {
-    let engine = cykv::CyStore::open(current_dir().unwrap(), Box::new(NoCacheManager)).unwrap();
-    let addr = SocketAddr::new(IpAddr::from_str("127.0.0.1").unwrap(), SERVER_PORT);
-    let server = cykv::Server::new(engine, addr).unwrap();
-    server.run().unwrap();
+    let config = match parse_args(env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let settings = match validate(config) {
+        Ok(settings) => settings,
+        Err(problems) => {
+            eprintln!("invalid settings:");
+            for problem in problems {
+                eprintln!("    {}", problem);
+            }
+            process::exit(2);
+        }
+    };
+
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(settings.log_level);
+
+    if let Err(e) = run(settings) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(settings: Settings) -> Result<()> {
+    let store = CyStore::open_with_options(
+        settings.data_dir.clone(),
+        settings.cache_manager,
+        settings.store,
+    )?;
+    let server = Server::with_options(store, settings.addr, settings.server)?;
+
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        if shutdown.is_shutdown() {
+            error!("exiting before the shutdown finished");
+            process::exit(1);
+        }
+        info!("received a signal to stop");
+        shutdown.shutdown();
+    })
+    .map_err(io::Error::other)?;
+
+    info!(
+        "listening on {}, the data is in {}",
+        server.local_addr()?,
+        settings.data_dir.display()
+    );
+    if let Some(resp_addr) = server.local_resp_addr()? {
+        info!("serving the Redis clients on {}", resp_addr);
+    }
+    // The store is synced, and its directory unlocked when the server is dropped
+    server.run()
+}
+
+// Read the config file and apply the options over it, None if the help is asked
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Option<Config>> {
+    let mut options = Vec::new();
+    let mut config_path = None;
+    let mut args = args.peekable();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name.to_owned(),
+            None => return Err(invalid(format!("unexpected argument {}", arg))),
+        };
+        // --name=value or --name value, --write-back takes no value
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name.to_owned(), value.to_owned()),
+            None if name == "write-back" => (name, "true".to_owned()),
+            None => match args.next() {
+                Some(value) => (name, value),
+                None => return Err(invalid(format!("missing the value of --{}", name))),
+            },
+        };
+
+        match name.as_str() {
+            "config" => config_path = Some(value),
+            _ => options.push((name, value)),
+        }
+    }
+
+    let mut config = match config_path {
+        Some(path) => {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| invalid(format!("can't read the config file {}: {}", path, e)))?;
+            serde_json::from_str(&text)
+                .map_err(|e| invalid(format!("invalid config file {}: {}", path, e)))?
+        }
+        None => Config::default(),
+    };
+    for (name, value) in options {
+        apply(&mut config, &name, &value)?;
+    }
+
+    Ok(Some(config))
+}
+
+fn apply(config: &mut Config, name: &str, value: &str) -> Result<()> {
+    match name {
+        "addr" => config.addr = value.to_owned(),
+        "resp-addr" => config.resp_addr = value.to_owned(),
+        "data-dir" => config.data_dir = PathBuf::from(value),
+        "cache" => config.cache = value.to_owned(),
+        "cache-bytes" => config.cache_bytes = Some(parse_bytes(name, value)?),
+        "cache-policy" => config.cache_policy = Some(value.to_owned()),
+        "chunk-size" => config.chunk_size = Some(parse_bytes(name, value)? as usize),
+        "write-back" => config.write_back = parse_number(name, value)?,
+        "flush-interval-ms" => config.flush_interval_ms = Some(parse_number(name, value)?),
+        "value-cache-bytes" => config.value_cache_bytes = parse_bytes(name, value)?,
+        "durability" => config.durability = value.to_owned(),
+        "sync-interval-ms" => config.sync_interval_ms = Some(parse_number(name, value)?),
+        "compact-threshold" => config.compact_threshold = parse_bytes(name, value)?,
+        "workers" => config.workers = parse_number(name, value)?,
+        "max-connections" => config.max_connections = parse_number(name, value)?,
+        "idle-timeout-ms" => config.idle_timeout_ms = parse_number(name, value)?,
+        "read-timeout-ms" => config.read_timeout_ms = parse_number(name, value)?,
+        "queue-timeout-ms" => config.queue_timeout_ms = parse_number(name, value)?,
+        "shutdown-timeout-ms" => config.shutdown_timeout_ms = parse_number(name, value)?,
+        "log-level" => config.log_level = value.to_owned(),
+        _ => return Err(invalid(format!("unknown option --{}", name))),
+    }
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value {} of --{}", value, name)))
+}
+
+// A number of bytes, with an optional K, M or G suffix
+fn parse_bytes(name: &str, value: &str) -> Result<u64> {
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&value[..i], 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&value[..i], 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&value[..i], 30),
+        _ => (value, 0),
+    };
+    let number: u64 = parse_number(name, number)?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| invalid(format!("--{} is too large", name)))
+}
+
+fn invalid(message: String) -> CyKvError {
+    CyKvError::InvalidArgument(message)
+}
+
+// Check the settings, and report all the problems at once
+fn validate(config: Config) -> std::result::Result<Settings, Vec<String>> {
+    let mut problems = Vec::new();
+    let lru = config.cache == "lru";
+
+    let addr = resolve("addr", &config.addr, &mut problems);
+    let resp_addr = match config.resp_addr.as_str() {
+        "" => None,
+        resp_addr => resolve("resp_addr", resp_addr, &mut problems),
+    };
+
+    if config.data_dir.exists() && !config.data_dir.is_dir() {
+        problems.push(format!(
+            "data_dir {} isn't a directory",
+            config.data_dir.display()
+        ));
+    }
+
+    if !["none", "lru", "mmap"].contains(&config.cache.as_str()) {
+        problems.push(format!(
+            "invalid cache {}, expected none, lru or mmap",
+            config.cache
+        ));
+    }
+    if !lru {
+        let lru_settings = [
+            ("cache_bytes", config.cache_bytes.is_some()),
+            ("cache_policy", config.cache_policy.is_some()),
+            ("chunk_size", config.chunk_size.is_some()),
+            ("write_back", config.write_back),
+            ("flush_interval_ms", config.flush_interval_ms.is_some()),
+        ];
+        for (name, _) in lru_settings.iter().filter(|(_, set)| *set) {
+            problems.push(format!("{} needs the lru cache", name));
+        }
+    }
+
+    let cache_bytes = config.cache_bytes.unwrap_or(DEFAULT_CACHE_BYTES);
+    let chunk_size = config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 {
+        problems.push("chunk_size must be positive".to_owned());
+    } else if lru && cache_bytes < chunk_size as u64 {
+        problems.push(format!(
+            "cache_bytes {} is smaller than a chunk of {} bytes",
+            cache_bytes, chunk_size
+        ));
+    }
+    let policy = match config.cache_policy.as_deref().unwrap_or("lru") {
+        "lru" => EvictionPolicyKind::Lru,
+        "clock" => EvictionPolicyKind::Clock,
+        "slru" => EvictionPolicyKind::SegmentedLru,
+        "tinylfu" => EvictionPolicyKind::TinyLfu,
+        policy => {
+            problems.push(format!(
+                "invalid cache_policy {}, expected lru, clock, slru or tinylfu",
+                policy
+            ));
+            EvictionPolicyKind::Lru
+        }
+    };
+    if config.flush_interval_ms.is_some() && !config.write_back {
+        problems.push("flush_interval_ms needs write_back".to_owned());
+    }
+    if config.flush_interval_ms == Some(0) {
+        problems.push("flush_interval_ms must be positive".to_owned());
+    }
+
+    let durability = match config.durability.as_str() {
+        "buffered" => Durability::Buffered,
+        "sync" => Durability::Sync,
+        "periodic" => Durability::Periodic(Duration::from_millis(
+            config.sync_interval_ms.unwrap_or(DEFAULT_SYNC_INTERVAL_MS),
+        )),
+        durability => {
+            problems.push(format!(
+                "invalid durability {}, expected buffered, sync or periodic",
+                durability
+            ));
+            Durability::Buffered
+        }
+    };
+    if config.sync_interval_ms.is_some() && config.durability != "periodic" {
+        problems.push("sync_interval_ms needs the periodic durability".to_owned());
+    }
+    if config.sync_interval_ms == Some(0) {
+        problems.push("sync_interval_ms must be positive".to_owned());
+    }
+
+    if config.compact_threshold == 0 {
+        problems.push("compact_threshold must be positive".to_owned());
+    }
+    if config.workers == 0 {
+        problems.push("workers must be positive".to_owned());
+    }
+    if config.max_connections == 0 {
+        problems.push("max_connections must be positive".to_owned());
+    }
+    let log_level = match config.log_level.parse() {
+        Ok(level) => level,
+        Err(_) => {
+            problems.push(format!(
+                "invalid log_level {}, expected off, error, warn, info, debug or trace",
+                config.log_level
+            ));
+            LevelFilter::Info
+        }
+    };
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    // Created only once the settings are valid, so a mistyped command leaves nothing behind
+    if let Err(e) = fs::create_dir_all(&config.data_dir) {
+        return Err(vec![format!(
+            "can't create data_dir {}: {}",
+            config.data_dir.display(),
+            e
+        )]);
+    }
+
+    let cache_manager: Box<dyn CacheManager> = match config.cache.as_str() {
+        "lru" => {
+            let cache_manager = LruCacheManager::with_options(LruCacheOptions {
+                cache_bytes,
+                policy,
+                chunk_size,
+                ..LruCacheOptions::default()
+            });
+            match config.write_back {
+                true => Box::new(
+                    cache_manager.write_back(config.flush_interval_ms.map(Duration::from_millis)),
+                ),
+                false => Box::new(cache_manager),
+            }
+        }
+        "mmap" => Box::new(MmapCacheManager::new()),
+        _ => Box::new(NoCacheManager),
+    };
+
+    Ok(Settings {
+        addr: addr.unwrap(),
+        data_dir: config.data_dir,
+        cache_manager,
+        store: StoreOptions {
+            value_cache_bytes: config.value_cache_bytes,
+            compact_threshold: config.compact_threshold,
+            durability,
+        },
+        server: ServerOptions {
+            workers: config.workers,
+            max_connections: config.max_connections,
+            idle_timeout: timeout(config.idle_timeout_ms),
+            read_timeout: timeout(config.read_timeout_ms),
+            queue_timeout: timeout(config.queue_timeout_ms),
+            shutdown_timeout: Duration::from_millis(config.shutdown_timeout_ms),
+            resp_addr,
+        },
+        log_level,
+    })
+}
+
+// The first address of a host and port, None after reporting the problem
+fn resolve(name: &str, addr: &str, problems: &mut Vec<String>) -> Option<SocketAddr> {
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Some(addr),
+        Ok(None) => {
+            problems.push(format!("{} {} resolves to no address", name, addr));
+            None
+        }
+        Err(e) => {
+            problems.push(format!("invalid {} {}: {}", name, addr, e));
+            None
+        }
+    }
+}
+
+// 0 waits forever
+fn timeout(ms: u64) -> Option<Duration> {
+    match ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+// Log to stderr, the level is filtered by log::max_level()
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
 }
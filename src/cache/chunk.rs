@@ -1,8 +1,10 @@
-use crate::cache::CHUNK_SIZE;
+use crate::cache::{CacheCounters, DEFAULT_CHUNK_SIZE};
+use crate::utils::read_at;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
+use std::sync::Arc;
 
 pub enum State {
     None = 0,
@@ -45,7 +47,7 @@ impl ChunkState {
 }
 
 pub struct Chunk {
-    buf: [u8; CHUNK_SIZE],
+    buf: Box<[u8]>, // the chunk size is the length of the buffer
     len: usize,
 
     // Attached file
@@ -58,17 +60,35 @@ pub struct Chunk {
     // from lowest to highest bit:
     // empty, dirty
     pub state: ChunkState,
+
+    counters: Arc<CacheCounters>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
+        Chunk::with_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Self {
+        Chunk::with_counters(size, Arc::default())
+    }
+
+    pub(crate) fn with_counters(size: usize, counters: Arc<CacheCounters>) -> Self {
+        counters.add_resident(size as u64);
         Self {
-            buf: [0; CHUNK_SIZE],
+            buf: vec![0; size].into_boxed_slice(),
             len: 0,
             file: None,
             file_id: 0,
             index: 0,
             state: ChunkState::with_state(State::Empty),
+            counters,
         }
     }
 
@@ -79,6 +99,7 @@ impl Chunk {
             .create(true)
             .read(true)
             .write(true)
+            .truncate(false)
             .open(path)?;
         self.file_id = file_id;
         self.attach_file(file, index);
@@ -90,6 +111,11 @@ impl Chunk {
         self.file.is_some()
     }
 
+    // Whether the chunk holds the index-th chunk of the file
+    pub fn is_attached(&self, file_id: u32, index: usize) -> bool {
+        self.has_file() && self.file_id == file_id && self.index == index
+    }
+
     pub fn clear(&mut self) -> io::Result<()> {
         self.store()?;
         self.file = None;
@@ -99,22 +125,54 @@ impl Chunk {
     fn attach_file(&mut self, file: File, index: usize) {
         self.file = Some(file);
         self.index = index;
-        // self.cur_offset = 0;
+        self.len = 0;
+        self.state.assign(State::Empty);
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    // The offset of the chunk in the file
+    fn pos(&self) -> u64 {
+        (self.index * self.buf.len()) as u64
     }
 
-    // Only read() may call load()
+    // Only read() and write() may call load(),
+    // when the chunk is empty
     fn load(&mut self) -> io::Result<usize> {
-        match &mut self.file {
-            Some(file) => {
-                file.seek(SeekFrom::Start((self.index * CHUNK_SIZE) as u64))?;
-                self.len = file.read(&mut self.buf)?;
-            }
-            None => {
-                self.len = 0;
+        self.load_ahead(0)?;
+        Ok(self.len)
+    }
+
+    // Load the empty chunk together with the following chunks in one read,
+    // return the data of the following chunks
+    pub(crate) fn load_ahead(&mut self, chunks: usize) -> io::Result<Vec<u8>> {
+        let size = self.buf.len();
+        let mut data = vec![0; size * (chunks + 1)];
+        let mut len = 0;
+        if let Some(file) = &self.file {
+            while len < data.len() {
+                match read_at(file, &mut data[len..], self.pos() + len as u64)? {
+                    0 => break,
+                    read => len += read,
+                }
             }
         }
+        data.truncate(len);
+        CacheCounters::add(&self.counters.misses, 1);
+        CacheCounters::add(&self.counters.bytes_loaded, len as u64);
 
-        Ok(self.len)
+        let ahead = data.split_off(len.min(size));
+        self.fill(&data);
+        Ok(ahead)
+    }
+
+    // Fill the empty chunk with the data loaded from the file
+    pub(crate) fn fill(&mut self, data: &[u8]) {
+        self.buf[..data.len()].copy_from_slice(data);
+        self.len = data.len();
+        self.state.clear(State::Empty);
     }
 
     // Only attach() and drop() may call store()
@@ -125,14 +183,16 @@ impl Chunk {
             return Ok(0);
         }
 
+        let pos = self.pos();
         match &mut self.file {
             Some(file) => {
-                file.seek(SeekFrom::Start((self.index * CHUNK_SIZE) as u64))?;
-                let len = file.write(&self.buf[..self.len])?;
+                file.seek(SeekFrom::Start(pos))?;
+                file.write_all(&self.buf[..self.len])?;
                 file.sync_data()?;
 
                 self.state.clear(State::Dirty);
-                Ok(len)
+                CacheCounters::add(&self.counters.write_backs, 1);
+                Ok(self.len)
             }
             None => Ok(0),
         }
@@ -141,29 +201,35 @@ impl Chunk {
     pub(crate) fn read(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
         if self.state.is_empty() {
             self.load()?;
+        } else {
+            CacheCounters::add(&self.counters.hits, 1);
+        }
+
+        let offset = offset as usize;
+        if offset >= self.len {
+            return Ok(0);
         }
 
         let mut buf = buf;
-        let len = buf.write(&self.buf[offset as usize..self.len])?;
+        let len = buf.write(&self.buf[offset..self.len])?;
 
         Ok(len)
     }
 
     pub(crate) fn write(&mut self, buf: &[u8], offset: u64) -> io::Result<usize> {
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Ok(0);
         }
 
-        let mut cnt = 0;
-        let offset = offset as usize;
-        for i in offset..CHUNK_SIZE {
-            self.buf[i] = buf[i - offset as usize];
-            cnt += 1;
-            if i - offset + 1 == buf.len() {
-                break;
-            }
+        // Load the bytes before offset, they are written back together
+        if self.state.is_empty() {
+            self.load()?;
         }
-        self.len = offset + cnt;
+
+        let offset = offset as usize;
+        let cnt = buf.len().min(self.buf.len() - offset);
+        self.buf[offset..offset + cnt].copy_from_slice(&buf[..cnt]);
+        self.len = self.len.max(offset + cnt);
 
         self.state.set(State::Dirty);
 
@@ -174,11 +240,17 @@ impl Chunk {
         self.store()?;
         Ok(())
     }
+
+    // Drop the unwritten data, called when the attached file is removed
+    pub(crate) fn discard(&mut self) {
+        self.state.clear(State::Dirty);
+    }
 }
 
 impl Drop for Chunk {
     fn drop(&mut self) {
-        self.store();
+        let _ = self.store();
+        CacheCounters::sub(&self.counters.resident_bytes, self.buf.len() as u64);
     }
 }
 
@@ -0,0 +1,543 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// `EvictionPolicy` decides which entries the chunk cache keeps,
+/// it holds at most `capacity` entries.
+pub trait EvictionPolicy<K, V>: Send {
+    // Get the value and record the access
+    fn get(&mut self, key: &K) -> Option<&V>;
+
+    // Insert the entry, return the evicted one,
+    // which is the inserted entry itself if the policy doesn't admit it
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)>;
+
+    // Remove the entry
+    fn pop(&mut self, key: &K) -> Option<V>;
+
+    // Whether the key is cached, without recording an access
+    fn contains(&self, key: &K) -> bool;
+
+    // Change the capacity, return the entries evicted to fit in it
+    fn resize(&mut self, capacity: usize) -> Vec<(K, V)>;
+
+    // The key to evict by the next insertion if the policy is full
+    fn victim(&self) -> Option<&K>;
+
+    fn keys(&self) -> Vec<K>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn capacity(&self) -> usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicyKind {
+    // Least recently used
+    Lru,
+    // Second-chance approximation of LRU
+    Clock,
+    // LRU with a probation and a protected segment, entries hit twice are protected
+    SegmentedLru,
+    // Segmented LRU behind a frequency-based admission filter
+    TinyLfu,
+}
+
+pub fn new_policy<K, V>(kind: EvictionPolicyKind, capacity: usize) -> Box<dyn EvictionPolicy<K, V>>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Send + 'static,
+{
+    match kind {
+        EvictionPolicyKind::Lru => Box::new(LruPolicy::new(capacity)),
+        EvictionPolicyKind::Clock => Box::new(ClockPolicy::new(capacity)),
+        EvictionPolicyKind::SegmentedLru => Box::new(SegmentedLruPolicy::new(capacity)),
+        EvictionPolicyKind::TinyLfu => Box::new(TinyLfuPolicy::new(capacity)),
+    }
+}
+
+pub struct LruPolicy<K: Hash + Eq, V> {
+    entries: lru::LruCache<K, V>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq, V> LruPolicy<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: lru::LruCache::unbounded(),
+            capacity,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send, V: Send> EvictionPolicy<K, V> for LruPolicy<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+        if self.entries.contains(&key) {
+            self.entries.put(key, value);
+            return None;
+        }
+
+        let evicted = if self.entries.len() >= self.capacity {
+            self.entries.pop_lru()
+        } else {
+            None
+        };
+        self.entries.put(key, value);
+        evicted
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        self.entries.pop(key)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.entries.contains(key)
+    }
+
+    fn resize(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while self.entries.len() > capacity {
+            evicted.extend(self.entries.pop_lru());
+        }
+        evicted
+    }
+
+    fn victim(&self) -> Option<&K> {
+        self.entries.peek_lru().map(|(key, _)| key)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+struct ClockSlot<K, V> {
+    key: K,
+    value: V,
+    referenced: bool,
+}
+
+/// The entries sit in a ring with a reference bit,
+/// the hand clears the set bits and evicts the first entry with a clear bit.
+pub struct ClockPolicy<K, V> {
+    slots: Vec<Option<ClockSlot<K, V>>>,
+    index: HashMap<K, usize>, // map key to the slot
+    free: Vec<usize>,
+    hand: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> ClockPolicy<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: Vec::new(),
+            index: HashMap::new(),
+            free: Vec::new(),
+            hand: 0,
+            capacity,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.hand = (self.hand + 1) % self.slots.len();
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send, V: Send> EvictionPolicy<K, V> for ClockPolicy<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let slot = self.slots[*self.index.get(key)?].as_mut().unwrap();
+        slot.referenced = true;
+        Some(&slot.value)
+    }
+
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+        if let Some(&i) = self.index.get(&key) {
+            let slot = self.slots[i].as_mut().unwrap();
+            slot.value = value;
+            slot.referenced = true;
+            return None;
+        }
+
+        let new_slot = ClockSlot {
+            key: key.clone(),
+            value,
+            referenced: false,
+        };
+        if let Some(i) = self.free.pop() {
+            self.slots[i] = Some(new_slot);
+            self.index.insert(key, i);
+            return None;
+        }
+        if self.slots.len() < self.capacity {
+            self.slots.push(Some(new_slot));
+            self.index.insert(key, self.slots.len() - 1);
+            return None;
+        }
+
+        loop {
+            let slot = self.slots[self.hand].as_mut().unwrap();
+            if !slot.referenced {
+                break;
+            }
+            slot.referenced = false;
+            self.advance();
+        }
+
+        let i = self.hand;
+        let evicted = self.slots[i].replace(new_slot).unwrap();
+        self.index.remove(&evicted.key);
+        self.index.insert(key, i);
+        self.advance();
+
+        Some((evicted.key, evicted.value))
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        let i = self.index.remove(key)?;
+        self.free.push(i);
+        self.slots[i].take().map(|slot| slot.value)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    fn resize(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while self.index.len() > capacity {
+            // Sweep as put() does, skipping the free slots
+            loop {
+                match &mut self.slots[self.hand] {
+                    Some(slot) if !slot.referenced => break,
+                    Some(slot) => slot.referenced = false,
+                    None => {}
+                }
+                self.advance();
+            }
+
+            let slot = self.slots[self.hand].take().unwrap();
+            self.index.remove(&slot.key);
+            self.free.push(self.hand);
+            evicted.push((slot.key, slot.value));
+            self.advance();
+        }
+
+        // Pack the ring into the new capacity, keeping the order from the hand
+        if self.slots.len() > capacity {
+            self.slots.rotate_left(self.hand);
+            let slots = std::mem::take(&mut self.slots);
+            self.slots = slots.into_iter().flatten().map(Some).collect();
+            self.index = self
+                .slots
+                .iter()
+                .enumerate()
+                .map(|(i, slot)| (slot.as_ref().unwrap().key.clone(), i))
+                .collect();
+            self.free.clear();
+            self.hand = 0;
+        }
+
+        evicted
+    }
+
+    fn victim(&self) -> Option<&K> {
+        if !self.free.is_empty() || self.slots.len() < self.capacity {
+            return None;
+        }
+
+        // The first entry the hand would find with a clear bit,
+        // or the one under the hand if all bits are set
+        let len = self.slots.len();
+        (0..len)
+            .map(|i| self.slots[(self.hand + i) % len].as_ref().unwrap())
+            .find(|slot| !slot.referenced)
+            .or_else(|| self.slots.get(self.hand)?.as_ref())
+            .map(|slot| &slot.key)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.index.keys().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+// The share of the protected segment in a segmented LRU
+const PROTECTED_RATIO: f64 = 0.8;
+
+/// New entries enter the probation segment, and are promoted to the protected segment
+/// when hit again, so a scan touching each entry once only flushes the probation segment.
+pub struct SegmentedLruPolicy<K: Hash + Eq, V> {
+    probation: lru::LruCache<K, V>,
+    protected: lru::LruCache<K, V>,
+    protected_capacity: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq, V> SegmentedLruPolicy<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            probation: lru::LruCache::unbounded(),
+            protected: lru::LruCache::unbounded(),
+            protected_capacity: (capacity as f64 * PROTECTED_RATIO) as usize,
+            capacity,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send, V: Send> EvictionPolicy<K, V> for SegmentedLruPolicy<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if self.protected.contains(key) {
+            return self.protected.get(key);
+        }
+
+        let value = self.probation.pop(key)?;
+        if self.protected_capacity == 0 {
+            self.probation.put(key.clone(), value);
+            return self.probation.get(key);
+        }
+
+        // Promote, the protected segment overflows to the probation segment
+        if self.protected.len() >= self.protected_capacity {
+            if let Some((demoted_key, demoted_value)) = self.protected.pop_lru() {
+                self.probation.put(demoted_key, demoted_value);
+            }
+        }
+        self.protected.put(key.clone(), value);
+        self.protected.get(key)
+    }
+
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+        if self.protected.contains(&key) {
+            self.protected.put(key, value);
+            return None;
+        }
+        if self.probation.contains(&key) {
+            self.probation.put(key, value);
+            return None;
+        }
+
+        let evicted = if self.len() >= self.capacity {
+            self.probation
+                .pop_lru()
+                .or_else(|| self.protected.pop_lru())
+        } else {
+            None
+        };
+        self.probation.put(key, value);
+        evicted
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        self.probation.pop(key).or_else(|| self.protected.pop(key))
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.probation.contains(key) || self.protected.contains(key)
+    }
+
+    fn resize(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.capacity = capacity;
+        self.protected_capacity = (capacity as f64 * PROTECTED_RATIO) as usize;
+
+        let mut evicted = Vec::new();
+        while self.len() > capacity {
+            evicted.extend(
+                self.probation
+                    .pop_lru()
+                    .or_else(|| self.protected.pop_lru()),
+            );
+        }
+        while self.protected.len() > self.protected_capacity {
+            if let Some((demoted_key, demoted_value)) = self.protected.pop_lru() {
+                self.probation.put(demoted_key, demoted_value);
+            }
+        }
+        evicted
+    }
+
+    fn victim(&self) -> Option<&K> {
+        if self.len() < self.capacity {
+            return None;
+        }
+
+        self.probation
+            .peek_lru()
+            .or_else(|| self.protected.peek_lru())
+            .map(|(key, _)| key)
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.probation
+            .iter()
+            .chain(self.protected.iter())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.probation.len() + self.protected.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+// Count-min sketch of the access frequencies, the counters saturate at 15
+// and are halved after every sample_size accesses, so old popularity fades
+struct FrequencySketch {
+    rows: [Vec<u8>; 4],
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> Self {
+        let width = capacity.max(16).next_power_of_two();
+        Self {
+            rows: [
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+                vec![0; width],
+            ],
+            mask: width - 1,
+            additions: 0,
+            sample_size: capacity.max(16) * 10,
+        }
+    }
+
+    fn slots<K: Hash>(&self, key: &K) -> [usize; 4] {
+        let mut slots = [0; 4];
+        for (seed, slot) in slots.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            *slot = hasher.finish() as usize & self.mask;
+        }
+        slots
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for (row, slot) in self.slots(key).iter().enumerate() {
+            let counter = &mut self.rows[row][*slot];
+            *counter = (*counter + 1).min(15);
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            for row in self.rows.iter_mut() {
+                row.iter_mut().for_each(|counter| *counter /= 2);
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.slots(key)
+            .iter()
+            .enumerate()
+            .map(|(row, slot)| self.rows[row][*slot])
+            .min()
+            .unwrap()
+    }
+}
+
+/// A segmented LRU which admits a new entry only if it has been accessed
+/// more often than the entry it would evict, so one-off accesses like a long scan
+/// can't flush the popular entries.
+pub struct TinyLfuPolicy<K: Hash + Eq, V> {
+    main: SegmentedLruPolicy<K, V>,
+    sketch: FrequencySketch,
+}
+
+impl<K: Hash + Eq, V> TinyLfuPolicy<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            main: SegmentedLruPolicy::new(capacity),
+            sketch: FrequencySketch::new(capacity),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send, V: Send> EvictionPolicy<K, V> for TinyLfuPolicy<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        self.sketch.increment(key);
+        self.main.get(key)
+    }
+
+    // The accesses are counted by get(), a miss is followed by a put()
+    fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if let Some(victim) = self.main.victim() {
+            if victim != &key && self.sketch.frequency(&key) <= self.sketch.frequency(victim) {
+                return Some((key, value));
+            }
+        }
+        self.main.put(key, value)
+    }
+
+    fn pop(&mut self, key: &K) -> Option<V> {
+        self.main.pop(key)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.main.contains(key)
+    }
+
+    // The frequencies are counted again in a sketch sized for the new capacity
+    fn resize(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.sketch = FrequencySketch::new(capacity);
+        self.main.resize(capacity)
+    }
+
+    fn victim(&self) -> Option<&K> {
+        self.main.victim()
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.main.keys()
+    }
+
+    fn len(&self) -> usize {
+        self.main.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.main.capacity()
+    }
+}
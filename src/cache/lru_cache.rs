@@ -1,120 +1,504 @@
+use crate::cache::write_back::WriteBack;
 use crate::cache::*;
 use std::cmp::{max, min};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fs::{self};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// The default number of the independently locked shards of the chunk cache
+pub const DEFAULT_CHUNK_SHARDS: usize = 16;
+// The default limit of the chunks loaded ahead of a sequential reader
+pub const DEFAULT_MAX_READAHEAD: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct LruCacheOptions {
+    pub cache_bytes: u64,
+    pub policy: EvictionPolicyKind,
+    pub shards: usize,
+    // The unit of the cached data and of the disk reads and writes
+    pub chunk_size: usize,
+    // The most chunks loaded ahead of a sequential reader, 0 disables the readahead
+    pub max_readahead: usize,
+}
+
+impl Default for LruCacheOptions {
+    fn default() -> Self {
+        Self {
+            cache_bytes: DEFAULT_CACHE_SIZE as u64,
+            policy: EvictionPolicyKind::Lru,
+            shards: DEFAULT_CHUNK_SHARDS,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_readahead: DEFAULT_MAX_READAHEAD,
+        }
+    }
+}
 
 // file_id and index
-#[derive(Eq, Hash, Clone, Copy)]
-struct CacheKey(u32, usize);
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub(crate) struct CacheKey(pub u32, pub usize);
 
-impl PartialEq for CacheKey {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0 && self.1 == other.1
+type ChunkPolicy = Box<dyn EvictionPolicy<CacheKey, Arc<Mutex<Chunk>>>>;
+type SharedChunkCache = Arc<ShardedChunkCache>;
+
+/// `ShardedChunkCache` partitions the chunks by the hash of their keys,
+/// each shard has its own lock and eviction policy, and an even share of the capacity,
+/// so the lookups of different chunks rarely wait for each other.
+/// If the capacity is smaller than the shard count, the keys go to as many shards
+/// as there are chunks, so no shard is left with a share of 0.
+pub(crate) struct ShardedChunkCache {
+    shards: Vec<Mutex<ChunkPolicy>>,
+    active: AtomicUsize, // the number of the shards in use
+}
+
+impl ShardedChunkCache {
+    fn new(policy: EvictionPolicyKind, capacity: usize, shards: usize) -> Self {
+        let active = active_shards(capacity, shards);
+        let shards = (0..shards.max(1))
+            .map(|i| Mutex::new(new_policy(policy, share(capacity, active, i))))
+            .collect();
+        Self {
+            shards,
+            active: AtomicUsize::new(active),
+        }
+    }
+
+    // Split the new capacity across the shards, return the evicted chunks
+    fn resize(&self, capacity: usize) -> Vec<(CacheKey, Arc<Mutex<Chunk>>)> {
+        // All the shards are locked, so no lookup runs while the keys move
+        let mut shards: Vec<_> = self.shards.iter().map(|s| s.lock().unwrap()).collect();
+        let active = active_shards(capacity, shards.len());
+        let mut moved = Vec::new();
+        if active != self.active.load(Ordering::Acquire) {
+            for shard in shards.iter_mut() {
+                for key in shard.keys() {
+                    moved.extend(shard.pop(&key).map(|chunk| (key, chunk)));
+                }
+            }
+            self.active.store(active, Ordering::Release);
+        }
+
+        let mut evicted = Vec::new();
+        for (i, shard) in shards.iter_mut().enumerate() {
+            evicted.extend(shard.resize(share(capacity, active, i)));
+        }
+        for (key, chunk) in moved {
+            evicted.extend(shards[shard_index(&key, active)].put(key, chunk));
+        }
+        evicted
+    }
+
+    fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().capacity())
+            .sum()
+    }
+
+    fn shard(&self, key: &CacheKey) -> MutexGuard<'_, ChunkPolicy> {
+        // Locked again if a resize changed the shards in use meanwhile
+        loop {
+            let active = self.active.load(Ordering::Acquire);
+            let shard = self.shards[shard_index(key, active)].lock().unwrap();
+            if self.active.load(Ordering::Acquire) == active {
+                return shard;
+            }
+        }
+    }
+
+    fn file_keys(&self, file_id: u32) -> Vec<CacheKey> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().keys())
+            .filter(|key| key.0 == file_id)
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+}
+
+// The number of the shards in use, each of them holds at least one chunk
+fn active_shards(capacity: usize, shards: usize) -> usize {
+    shards.clamp(1, capacity.max(1))
+}
+
+fn shard_index(key: &CacheKey, active: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % active
+}
+
+// The capacity of the i-th shard, the first shards in use take the remainder
+fn share(capacity: usize, active: usize, i: usize) -> usize {
+    match i < active {
+        true => capacity / active + usize::from(i < capacity % active),
+        false => 0,
+    }
+}
+
+/// `Readahead` detects the sequential readers of each file by the chunks they miss,
+/// a miss right after the chunks loaded for the previous one doubles the chunks
+/// to load ahead, up to the limit, and any other miss stops the readahead.
+struct Readahead {
+    max_chunks: usize,
+    streams: Mutex<HashMap<u32, (usize, usize)>>, // map file id to (expected index, window)
+}
+
+impl Readahead {
+    // Return the number of the chunks to load after the missed one
+    fn on_miss(&self, file_id: u32, index: usize) -> usize {
+        if self.max_chunks == 0 {
+            return 0;
+        }
+
+        let mut streams = self.streams.lock().unwrap();
+        let (expected, window) = streams.entry(file_id).or_insert((usize::MAX, 0));
+        *window = match *expected == index {
+            true => (*window * 2).clamp(1, self.max_chunks),
+            false => 0,
+        };
+        *expected = index + 1 + *window;
+        *window
+    }
+
+    fn forget(&self, file_id: u32) {
+        self.streams.lock().unwrap().remove(&file_id);
     }
 }
 
+/// `LruCacheManager` caches the chunks of the files in a shared chunk list,
+/// the chunks to keep are chosen by the eviction policy, LRU by default.
+/// The chunk list is split into shards to keep the concurrent readers from contending.
+/// The writes go through to the files by default, in the write-back mode
+/// the dirty chunks are written when evicted, flushed, or by the periodic flusher.
+/// The cached chunks never exceed the budget, only the reads and writes in progress
+/// hold their chunks, and the chunks loaded ahead, beyond it.
 pub struct LruCacheManager {
-    chunk_cache: Arc<Mutex<lru::LruCache<CacheKey, Arc<Mutex<Chunk>>>>>,
+    chunk_cache: SharedChunkCache,
+    counters: Arc<CacheCounters>,
+    write_back: Option<Arc<WriteBack>>,
+    readahead: Arc<Readahead>,
+    chunk_size: usize,
 }
 
 impl LruCacheManager {
     pub fn new(cache_bytes: u64) -> Self {
+        LruCacheManager::with_policy(cache_bytes, EvictionPolicyKind::Lru)
+    }
+
+    pub fn with_policy(cache_bytes: u64, policy: EvictionPolicyKind) -> Self {
+        LruCacheManager::with_shards(cache_bytes, policy, DEFAULT_CHUNK_SHARDS)
+    }
+
+    pub fn with_shards(cache_bytes: u64, policy: EvictionPolicyKind, shards: usize) -> Self {
+        LruCacheManager::with_options(LruCacheOptions {
+            cache_bytes,
+            policy,
+            shards,
+            ..LruCacheOptions::default()
+        })
+    }
+
+    pub fn with_options(options: LruCacheOptions) -> Self {
+        let chunk_size = options.chunk_size.max(1);
+        let capacity = (options.cache_bytes / chunk_size as u64) as usize;
         Self {
-            chunk_cache: Arc::new(Mutex::new(lru::LruCache::new(
-                (cache_bytes / CHUNK_SIZE as u64) as usize,
-            ))),
+            chunk_cache: Arc::new(ShardedChunkCache::new(
+                options.policy,
+                capacity,
+                options.shards,
+            )),
+            counters: Arc::default(),
+            write_back: None,
+            readahead: Arc::new(Readahead {
+                max_chunks: options.max_readahead,
+                streams: Mutex::default(),
+            }),
+            chunk_size,
         }
     }
+
+    // Buffer the writes in the chunks, and flush the dirty chunks every interval if given
+    pub fn write_back(mut self, flush_interval: Option<Duration>) -> Self {
+        let write_back = Arc::new(WriteBack::default());
+        if let Some(interval) = flush_interval {
+            WriteBack::spawn_flusher(&write_back, interval);
+        }
+        self.write_back = Some(write_back);
+        self
+    }
 }
 
 impl CacheManager for LruCacheManager {
     fn open(&self, path: &Path, file_id: u32) -> Box<dyn Cache> {
-        let len = fs::metadata(path).map_or(0, |metadata| metadata.len());
-        Box::new(LruCache::new(path, &self.chunk_cache, file_id, len))
+        let mut len = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        if let Some(written) = self.write_back.as_ref().and_then(|wb| wb.len(file_id)) {
+            len = max(len, written);
+        }
+        Box::new(LruCache::new(path, self, file_id, len))
+    }
+
+    fn evict_file(&self, file_id: u32) {
+        self.readahead.forget(file_id);
+        if let Some(write_back) = &self.write_back {
+            write_back.discard_file(file_id);
+        }
+
+        for key in self.chunk_cache.file_keys(file_id) {
+            let chunk = self.chunk_cache.shard(&key).pop(&key);
+            // Dropped out of the lock, a dirty chunk is stored on drop
+            drop(chunk);
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        let resident_chunks = self.chunk_cache.len() as u64;
+        let capacity_bytes = (self.chunk_cache.capacity() * self.chunk_size) as u64;
+        self.counters.snapshot(resident_chunks, capacity_bytes)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match &self.write_back {
+            Some(write_back) => write_back.flush_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, cache_bytes: u64) -> io::Result<()> {
+        let capacity = (cache_bytes / self.chunk_size as u64) as usize;
+        let evicted = self.chunk_cache.resize(capacity);
+        CacheCounters::add(&self.counters.evictions, evicted.len() as u64);
+        if let Some(write_back) = &self.write_back {
+            for (key, _) in &evicted {
+                write_back.flush_until(*key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_file(&self, from: u32, to: u32) {
+        self.evict_file(to);
+        self.readahead.forget(from);
+        if let Some(write_back) = &self.write_back {
+            write_back.rename_file(from, to);
+        }
+
+        for key in self.chunk_cache.file_keys(from) {
+            let chunk = self.chunk_cache.shard(&key).pop(&key);
+            if let Some(chunk) = chunk {
+                // The attached handle still refers to the renamed file
+                chunk.lock().unwrap().file_id = to;
+                let new_key = CacheKey(to, key.1);
+                let evicted = self.chunk_cache.shard(&new_key).put(new_key, chunk);
+                drop(evicted);
+            }
+        }
     }
 }
 
 /// `Cache` is an abstraction of `File`
 /// the cache caches the data in some chunks
-/// each chunk has size `CHUNK_SIZE`
-/// divide file data into multiple chunks: continuous `CHUNK_SIZE` bytes is a chunk
+/// each chunk has the chunk size of the manager
+/// divide file data into multiple chunks: continuous chunk size bytes is a chunk
 /// the i-th chunk has the `index i`
+/// the chunks are shared by all the caches of the same file,
+/// so a cache never holds a chunk evicted from the shared chunk list
 pub struct LruCache {
     path: PathBuf,
     file_id: u32,
     len: u64,
-    chunk_cache: Arc<Mutex<lru::LruCache<CacheKey, Arc<Mutex<Chunk>>>>>, // shared chunk list
-    chunk_map: HashMap<usize, Arc<Mutex<Chunk>>>,                        // map index to chunk node
+    chunk_cache: SharedChunkCache, // shared chunk list
+    counters: Arc<CacheCounters>,
+    write_back: Option<Arc<WriteBack>>,
+    readahead: Arc<Readahead>,
+    chunk_size: usize,
     cur_offset: u64,
 }
 
 impl LruCache {
-    fn new(
-        path: impl AsRef<Path>,
-        allocator: &Arc<Mutex<lru::LruCache<CacheKey, Arc<Mutex<Chunk>>>>>,
-        file_id: u32,
-        len: u64,
-    ) -> Self {
+    fn new(path: impl AsRef<Path>, manager: &LruCacheManager, file_id: u32, len: u64) -> Self {
         Self {
             path: path.as_ref().to_path_buf(),
             file_id,
             len,
-            chunk_cache: Arc::clone(allocator),
-            chunk_map: HashMap::new(),
+            chunk_cache: Arc::clone(&manager.chunk_cache),
+            counters: Arc::clone(&manager.counters),
+            write_back: manager.write_back.clone(),
+            readahead: Arc::clone(&manager.readahead),
+            chunk_size: manager.chunk_size,
             cur_offset: 0,
         }
     }
 
-    fn offset_to_index(offset: u64) -> usize {
-        (offset >> CHUNK_SIZE_SHIFT) as usize
+    fn offset_to_index(&self, offset: u64) -> usize {
+        (offset / self.chunk_size as u64) as usize
+    }
+
+    fn new_chunk(&self) -> Chunk {
+        Chunk::with_counters(self.chunk_size, Arc::clone(&self.counters))
     }
 
-    fn read_chunk(&mut self, offset: u64, index: usize, buf: &mut [u8]) -> io::Result<usize> {
-        if !self.chunk_map.contains_key(&index) {
-            let mut cache_guard = self.chunk_cache.lock().unwrap();
-            let key = CacheKey(self.file_id, index);
-            if !cache_guard.contains(&key) {
-                cache_guard.put(key, Arc::new(Mutex::new(Chunk::new())));
+    // Count the chunk evicted by inserting the key, and write it back before it's dropped
+    fn on_evicted(
+        &self,
+        key: CacheKey,
+        evicted: &Option<(CacheKey, Arc<Mutex<Chunk>>)>,
+    ) -> io::Result<()> {
+        if let Some((evicted_key, _)) = evicted {
+            if *evicted_key != key {
+                CacheCounters::add(&self.counters.evictions, 1);
+                if let Some(write_back) = &self.write_back {
+                    write_back.flush_until(*evicted_key)?;
+                }
             }
-            self.chunk_map
-                .insert(index, cache_guard.get(&key).unwrap().clone());
         }
+        Ok(())
+    }
+
+    // Get the chunk attached to the index-th chunk of the file
+    fn chunk(&self, index: usize) -> io::Result<Arc<Mutex<Chunk>>> {
+        let key = CacheKey(self.file_id, index);
+        let mut cache_guard = self.chunk_cache.shard(&key);
+        let chunk = match cache_guard.get(&key) {
+            Some(chunk) => {
+                let chunk = Arc::clone(chunk);
+                drop(cache_guard);
+                chunk
+            }
+            None => {
+                // A dirty chunk dropped by the policy is still held by the write-back
+                let chunk = match self.write_back.as_ref().and_then(|wb| wb.get(&key)) {
+                    Some(chunk) => chunk,
+                    None => Arc::new(Mutex::new(self.new_chunk())),
+                };
+                // The chunk is used uncached if the policy doesn't admit it
+                let evicted = cache_guard.put(key, Arc::clone(&chunk));
+                // Dropped out of the lock, a dirty chunk is stored on drop
+                drop(cache_guard);
+                self.on_evicted(key, &evicted)?;
+                drop(evicted);
+                chunk
+            }
+        };
 
-        let chunk = self.chunk_map.get(&index).unwrap();
-        let mut chunk = chunk.lock().unwrap();
-        // Need load data from disk
-        if chunk.file_id != self.file_id || chunk.has_file() {
-            chunk.attach(&self.path, self.file_id, index)?;
+        {
+            let mut guard = chunk.lock().unwrap();
+            // Need load data from disk
+            if !guard.is_attached(self.file_id, index) {
+                guard.attach(&self.path, self.file_id, index)?;
+            }
         }
 
-        return chunk.read(buf, offset - (index * CHUNK_SIZE) as u64);
+        Ok(chunk)
     }
 
-    fn write_chunk(&mut self, offset: u64, index: usize, buf: &[u8]) -> io::Result<usize> {
-        if !self.chunk_map.contains_key(&index) {
-            let mut cache_guard = self.chunk_cache.lock().unwrap();
-            let key = CacheKey(self.file_id, index);
-            if !cache_guard.contains(&key) {
-                cache_guard.put(key, Arc::new(Mutex::new(Chunk::new())));
+    fn read_chunk(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let index = self.offset_to_index(offset);
+        let chunk = self.chunk(index)?;
+        let mut guard = chunk.lock().unwrap();
+
+        let mut ahead = Vec::new();
+        if guard.state.is_empty() {
+            let window = self.readahead.on_miss(self.file_id, index);
+            if window > 0 {
+                ahead = guard.load_ahead(window)?;
             }
-            self.chunk_map
-                .insert(index, cache_guard.get(&key).unwrap().clone());
         }
+        let len = guard.read(buf, offset - (index * self.chunk_size) as u64)?;
+        // Released before the prefetch, which may evict it
+        drop(guard);
+        drop(chunk);
+
+        // The data loaded ahead is resident until it's copied to the chunks
+        let ahead_bytes = ahead.len() as u64;
+        self.counters.add_resident(ahead_bytes);
+        let prefetched = self.prefetch(index + 1, &ahead);
+        drop(ahead);
+        CacheCounters::sub(&self.counters.resident_bytes, ahead_bytes);
+        prefetched?;
+        Ok(len)
+    }
+
+    // Cache the chunks loaded ahead, from the start-th chunk of the file,
+    // only the full chunks are cached, as the logs are append-only,
+    // a full chunk never changes and can't be stale
+    fn prefetch(&self, start: usize, ahead: &[u8]) -> io::Result<()> {
+        for (i, data) in ahead.chunks_exact(self.chunk_size).enumerate() {
+            let key = CacheKey(self.file_id, start + i);
+            let mut cache_guard = self.chunk_cache.shard(&key);
+            let dirty = self.write_back.as_ref().and_then(|wb| wb.get(&key));
+            if cache_guard.contains(&key) || dirty.is_some() {
+                continue;
+            }
 
-        let chunk = self.chunk_map.get(&index).unwrap();
-        let mut chunk = chunk.lock().unwrap();
-        // Need load data from disk
-        if chunk.file_id != self.file_id || chunk.has_file() {
-            chunk.attach(&self.path, self.file_id, index)?;
+            let mut chunk = self.new_chunk();
+            chunk.attach(&self.path, self.file_id, start + i)?;
+            chunk.fill(data);
+            let evicted = cache_guard.put(key, Arc::new(Mutex::new(chunk)));
+            drop(cache_guard);
+            self.on_evicted(key, &evicted)?;
         }
 
-        let len = chunk.write(buf, offset - (index * CHUNK_SIZE) as u64)?;
-        chunk.sync()?;
-        Ok(len)
+        Ok(())
+    }
+
+    // Whether the chunk is the one the readers find for the key,
+    // it may have been evicted, and another one loaded, since it was got
+    fn is_current(
+        &self,
+        cache_guard: &mut ChunkPolicy,
+        key: &CacheKey,
+        chunk: &Arc<Mutex<Chunk>>,
+    ) -> bool {
+        if let Some(cached) = cache_guard.get(key) {
+            return Arc::ptr_eq(cached, chunk);
+        }
+        match self.write_back.as_ref().and_then(|wb| wb.get(key)) {
+            Some(dirty) => Arc::ptr_eq(&dirty, chunk),
+            None => true,
+        }
+    }
+
+    fn write_chunk(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let index = self.offset_to_index(offset);
+        let key = CacheKey(self.file_id, index);
+        loop {
+            let chunk = self.chunk(index)?;
+            // Write with the shard locked, so the chunk can't be evicted
+            // and loaded again from the file before the write reaches the file,
+            // or before it's held as dirty by the write-back
+            let mut cache_guard = self.chunk_cache.shard(&key);
+            if !self.is_current(&mut cache_guard, &key, &chunk) {
+                continue;
+            }
+
+            let mut guard = chunk.lock().unwrap();
+            let len = guard.write(buf, offset - (index * self.chunk_size) as u64)?;
+            match &self.write_back {
+                Some(write_back) => {
+                    drop(guard);
+                    write_back.mark_dirty(key, &chunk, offset + len as u64);
+                    // The dirty chunks the policy doesn't hold are written back at once,
+                    // so they can't pile up beyond the budget
+                    if !cache_guard.contains(&key) {
+                        write_back.flush_until(key)?;
+                    }
+                }
+                None => guard.sync()?,
+            }
+            return Ok(len);
+        }
     }
 }
 
@@ -126,47 +510,30 @@ impl Cache for LruCache {
 
 impl Read for LruCache {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.cur_offset >= self.len {
-            return Ok(0); // No more data
-        }
-
-        let (start, end) = (
-            LruCache::offset_to_index(self.cur_offset),
-            LruCache::offset_to_index(min(self.len, self.cur_offset + buf.len() as u64)),
-        );
+        let end = min(self.len, self.cur_offset + buf.len() as u64);
 
         let mut len = 0;
-        len += self.read_chunk(self.cur_offset, start, &mut buf[len..])?;
+        while self.cur_offset < end {
+            let want = (end - self.cur_offset) as usize;
+            let read = self.read_chunk(self.cur_offset, &mut buf[len..len + want])?;
+            if read == 0 {
+                break; // No more data
+            }
 
-        for i in start + 1..end {
-            len += self.read_chunk((i * CHUNK_SIZE) as u64, i, &mut buf[len..])?;
+            len += read;
+            self.cur_offset += read as u64;
         }
 
-        len += self.read_chunk(
-            min(self.len, self.cur_offset + buf.len() as u64),
-            end,
-            &mut buf[len..],
-        )?;
-
-        self.cur_offset += len as u64;
-
         Ok(len)
     }
 }
 
 impl Write for LruCache {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let (start, end) = (
-            LruCache::offset_to_index(self.cur_offset),
-            LruCache::offset_to_index(self.cur_offset + buf.len() as u64),
-        );
-
         let mut len = 0;
-        len += self.write_chunk(self.cur_offset, start, &buf[len..])?;
-        for i in start + 1..end {
-            len += self.write_chunk((i * CHUNK_SIZE) as u64, i, &buf[len..])?;
+        while len < buf.len() {
+            len += self.write_chunk(self.cur_offset + len as u64, &buf[len..])?;
         }
-        len += self.write_chunk(self.cur_offset + buf.len() as u64, end, &buf[len..])?;
 
         self.cur_offset += len as u64;
         self.len = max(self.len, self.cur_offset);
@@ -175,7 +542,10 @@ impl Write for LruCache {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        match &self.write_back {
+            Some(write_back) => write_back.flush_file(self.file_id),
+            None => Ok(()),
+        }
     }
 }
 
@@ -0,0 +1,237 @@
+use crate::cache::{Cache, CacheCounters, CacheManager, CacheStats};
+use crate::utils::write_all_at;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+// 64 KiB
+const TAIL_SIZE: usize = 64 << 10;
+
+// A log file opened by the caches, shared by all the caches of the same file id
+struct LogFile {
+    path: PathBuf,
+    map: RwLock<Option<Arc<Mmap>>>, // read-only mapping of the whole file
+    tail: Mutex<Tail>,
+}
+
+// A copy of the bytes last appended to the file, which the reads use
+// until the file is mapped again
+#[derive(Default)]
+struct Tail {
+    file: Option<File>, // opened by the first write
+    pos: u64,           // the position of buf in the file
+    buf: Vec<u8>,
+}
+
+impl Tail {
+    fn end(&self) -> u64 {
+        self.pos + self.buf.len() as u64
+    }
+}
+
+impl LogFile {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            map: RwLock::new(None),
+            tail: Mutex::new(Tail::default()),
+        }
+    }
+
+    // The mapping of the file, remapped if it doesn't cover len bytes
+    fn mapping(&self, len: u64, counters: &CacheCounters) -> io::Result<Option<Arc<Mmap>>> {
+        let mut map = self.map.write().unwrap();
+        if let Some(mapped) = &*map {
+            if mapped.len() as u64 >= len {
+                return Ok(Some(Arc::clone(mapped)));
+            }
+        }
+
+        let file = File::open(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(map.clone());
+        }
+        // SAFETY: the log files are append-only and never truncated,
+        // a compaction writes a new file and replaces the old one by renaming,
+        // so the mapped bytes are never changed or cut off
+        let mapped = Arc::new(unsafe { Mmap::map(&file)? });
+        CacheCounters::add(&counters.misses, 1);
+        CacheCounters::add(&counters.bytes_loaded, mapped.len() as u64);
+        *map = Some(Arc::clone(&mapped));
+        Ok(Some(mapped))
+    }
+}
+
+/// `MmapCacheManager` maps the log files read-only, and serves the reads from the mapped memory.
+/// The writes go to the file directly, like the writes of `NoCacheManager`,
+/// and the last `TAIL_SIZE` bytes appended are kept, so the reads of them are served
+/// from memory. A growing file is remapped when a read reaches beyond the kept bytes.
+/// The files are shared by all the caches of the same file id,
+/// a file is dropped when the engine removes or replaces it.
+/// The engine doesn't read a file while it's removed or replaced,
+/// so a mapping is never made of a replaced file.
+#[derive(Default)]
+pub struct MmapCacheManager {
+    files: Arc<RwLock<HashMap<u32, Arc<LogFile>>>>, // map file id to the opened file
+    counters: Arc<CacheCounters>,
+}
+
+impl MmapCacheManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remove_file(&self, files: &mut HashMap<u32, Arc<LogFile>>, file_id: u32) {
+        if let Some(file) = files.remove(&file_id) {
+            if file.map.read().unwrap().is_some() {
+                CacheCounters::add(&self.counters.evictions, 1);
+            }
+        }
+    }
+}
+
+impl CacheManager for MmapCacheManager {
+    fn open(&self, path: &Path, file_id: u32) -> Box<dyn Cache> {
+        let file = self.files.read().unwrap().get(&file_id).cloned();
+        let file = file.unwrap_or_else(|| {
+            let mut files = self.files.write().unwrap();
+            Arc::clone(
+                files
+                    .entry(file_id)
+                    .or_insert_with(|| Arc::new(LogFile::new(path))),
+            )
+        });
+        Box::new(MmapCache {
+            file,
+            counters: Arc::clone(&self.counters),
+            map: None,
+            offset: 0,
+        })
+    }
+
+    fn evict_file(&self, file_id: u32) {
+        self.remove_file(&mut self.files.write().unwrap(), file_id);
+    }
+
+    fn rename_file(&self, from: u32, to: u32) {
+        let mut files = self.files.write().unwrap();
+        for file_id in [from, to] {
+            self.remove_file(&mut files, file_id);
+        }
+    }
+
+    // A hit is a read served by an existing mapping, or the kept tail,
+    // a miss maps the file again
+    fn stats(&self) -> CacheStats {
+        let files = self.files.read().unwrap();
+        let maps: Vec<_> = files
+            .values()
+            .filter_map(|file| file.map.read().unwrap().clone())
+            .collect();
+        let mut stats = self.counters.snapshot(maps.len() as u64, 0);
+        stats.resident_bytes = maps.iter().map(|map| map.len() as u64).sum();
+        stats
+    }
+}
+
+pub struct MmapCache {
+    file: Arc<LogFile>,
+    counters: Arc<CacheCounters>,
+    map: Option<Arc<Mmap>>,
+    offset: u64,
+}
+
+impl Cache for MmapCache {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Read for MmapCache {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mapped_len = |map: &Option<Arc<Mmap>>| map.as_ref().map_or(0, |map| map.len() as u64);
+        if self.offset >= mapped_len(&self.map) {
+            self.map = self.file.map.read().unwrap().clone();
+        }
+        if self.offset >= mapped_len(&self.map) {
+            let tail = self.file.tail.lock().unwrap();
+            if tail.file.is_some() && self.offset >= tail.pos {
+                if self.offset >= tail.end() {
+                    return Ok(0); // No more data
+                }
+                let mut data = &tail.buf[(self.offset - tail.pos) as usize..];
+                let len = data.read(buf)?;
+                CacheCounters::add(&self.counters.hits, 1);
+                self.offset += len as u64;
+                return Ok(len);
+            }
+            drop(tail);
+            self.map = self.file.mapping(self.offset + 1, &self.counters)?;
+        }
+
+        let map = match &self.map {
+            Some(map) if self.offset < map.len() as u64 => map,
+            _ => return Ok(0), // No more data
+        };
+
+        let mut data = &map[self.offset as usize..];
+        let len = data.read(buf)?;
+        CacheCounters::add(&self.counters.hits, 1);
+        self.offset += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Write for MmapCache {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tail = self.file.tail.lock().unwrap();
+        if tail.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&self.file.path)?;
+            tail.file = Some(file);
+            tail.pos = self.offset;
+        }
+
+        write_all_at(tail.file.as_ref().unwrap(), buf, self.offset)?;
+
+        // The tail holds the bytes in a row, a write elsewhere starts a new one,
+        // and a full tail is dropped, the reads map the written bytes instead
+        if self.offset != tail.end() || tail.buf.len() + buf.len() > TAIL_SIZE {
+            tail.pos = self.offset;
+            tail.buf.clear();
+        }
+        tail.buf.extend_from_slice(buf);
+        self.offset += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.file.tail.lock().unwrap().file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for MmapCache {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.offset = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => {
+                let len = fs::metadata(&self.file.path).map_or(0, |metadata| metadata.len());
+                (len as i64 + offset) as u64
+            }
+            SeekFrom::Current(offset) => (self.offset as i64 + offset) as u64,
+        };
+
+        Ok(self.offset)
+    }
+}
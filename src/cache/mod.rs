@@ -1,16 +1,56 @@
 mod chunk;
+mod eviction;
 mod lru_cache;
+mod mmap_cache;
 mod no_cache;
+mod stats;
+mod write_back;
 
 pub use chunk::*;
+pub use eviction::*;
 pub use lru_cache::*;
+pub use mmap_cache::*;
 pub use no_cache::*;
+pub use stats::CacheStats;
 
-use std::io::{Read, Seek, Write};
+pub(crate) use stats::CacheCounters;
+
+use std::io::{self, Read, Seek, Write};
 use std::path::Path;
 
 pub trait CacheManager: Send + Sync {
     fn open(&self, path: &Path, file_id: u32) -> Box<dyn Cache>;
+
+    // Whether the engine should read the files through the caches,
+    // otherwise it reads the files with its own pooled handles
+    fn caches_reads(&self) -> bool {
+        true
+    }
+
+    // Called when the engine removes the file,
+    // the cached data of the file must not be served afterwards
+    fn evict_file(&self, _file_id: u32) {}
+
+    // Called when the engine renames the file with id from to the file with id to,
+    // which replaces the file with id to if it exists
+    fn rename_file(&self, _from: u32, _to: u32) {}
+
+    fn stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+
+    // Write back the buffered data of all the files
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Change the memory budget of the cached data at runtime
+    fn resize(&self, _cache_bytes: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the cache has no memory budget",
+        ))
+    }
 }
 pub trait Cache: Read + Write + Seek + Send + Sync {
     fn offset(&self) -> u64;
@@ -18,19 +58,18 @@ pub trait Cache: Read + Write + Seek + Send + Sync {
 
 pub enum CacheManagerEnum {}
 
-/// Cache layer of CyKV
-/// the same chunk wouldn't be written by more than 1 threads
-/// Bitcask guarantees whenever only one file would be written
-/// Conclusion: At most 1 thread write the same chunk
-/// proof:
-/// assume there are 2 threads write the same chunk
-/// writing happens when only the store() method is called, which is called when only the chunk is dropped and attach a new file
-/// drop happens when CyStore instance is dropped, that only happens in only one thread
-/// only situation:
-/// 2 threads attach a new file,
+// Cache layer of CyKV
+// the same chunk wouldn't be written by more than 1 threads
+// Bitcask guarantees whenever only one file would be written
+// Conclusion: At most 1 thread write the same chunk
+// proof:
+// assume there are 2 threads write the same chunk
+// writing happens when only the store() method is called, which is called when only the chunk is dropped and attach a new file
+// drop happens when CyStore instance is dropped, that only happens in only one thread
+// only situation:
+// 2 threads attach a new file,
 
 pub const DEFAULT_CACHE_SIZE: usize = 100 << 20;
 
 // 4KiB CHUNK
-const CHUNK_SIZE_SHIFT: u64 = 12;
-const CHUNK_SIZE: usize = 1 << CHUNK_SIZE_SHIFT;
+pub const DEFAULT_CHUNK_SIZE: usize = 4 << 10;
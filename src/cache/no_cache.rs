@@ -2,15 +2,18 @@ use crate::cache::{Cache, CacheManager};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Result, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct NoCacheManager;
 
 impl CacheManager for NoCacheManager {
-    fn open(&self, path: &Path, file_id: u32) -> Box<dyn Cache> {
+    fn open(&self, path: &Path, _file_id: u32) -> Box<dyn Cache> {
         Box::new(NoCache::new(path))
     }
+
+    fn caches_reads(&self) -> bool {
+        false
+    }
 }
 
 pub struct NoCache {
@@ -24,6 +27,7 @@ impl NoCache {
             .create(true)
             .read(true)
             .write(true)
+            .truncate(false)
             .open(path)
             .unwrap();
 
@@ -53,7 +57,7 @@ impl Write for NoCache {
     }
 
     fn flush(&mut self) -> Result<()> {
-        self.file.flush()
+        self.file.sync_data()
     }
 }
 
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Statistics of a cache manager, see `CacheManager::stats()`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    // Reads served from the cached data
+    pub hits: u64,
+    // Reads which had to load the data from disk
+    pub misses: u64,
+    // Cached units dropped to make room for others
+    pub evictions: u64,
+    // Dirty chunks written back to disk
+    pub write_backs: u64,
+    pub bytes_loaded: u64,
+    // Chunks, or mappings, currently held in memory
+    pub resident_chunks: u64,
+    // The memory of the chunk buffers alive, including the ones in use by the readers
+    // after being evicted, and the data loaded ahead
+    pub resident_bytes: u64,
+    // The highest resident_bytes so far, 0 if the cache doesn't track it
+    pub peak_resident_bytes: u64,
+    // The memory budget of the cached data, 0 if it's unbounded
+    pub capacity_bytes: u64,
+}
+
+// Counters shared by a cache manager and its caches
+#[derive(Default)]
+pub(crate) struct CacheCounters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    pub write_backs: AtomicU64,
+    pub bytes_loaded: AtomicU64,
+    pub resident_bytes: AtomicU64, // a gauge, decreased when a chunk is dropped
+    pub peak_resident_bytes: AtomicU64,
+}
+
+impl CacheCounters {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(counter: &AtomicU64, n: u64) {
+        counter.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    // Increase the resident gauge, and its peak
+    pub fn add_resident(&self, n: u64) {
+        let resident = self.resident_bytes.fetch_add(n, Ordering::Relaxed) + n;
+        self.peak_resident_bytes
+            .fetch_max(resident, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, resident_chunks: u64, capacity_bytes: u64) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            write_backs: self.write_backs.load(Ordering::Relaxed),
+            bytes_loaded: self.bytes_loaded.load(Ordering::Relaxed),
+            resident_chunks,
+            resident_bytes: self.resident_bytes.load(Ordering::Relaxed),
+            peak_resident_bytes: self.peak_resident_bytes.load(Ordering::Relaxed),
+            capacity_bytes,
+        }
+    }
+}
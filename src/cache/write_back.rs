@@ -0,0 +1,123 @@
+use crate::cache::lru_cache::CacheKey;
+use crate::cache::Chunk;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// `WriteBack` keeps the dirty chunks of a write-back chunk cache until they are flushed.
+/// The chunks of a file are flushed in the order of their indexes, and each chunk is synced
+/// before the next one is written, so a crash never leaves a later chunk of a log persisted
+/// while an earlier one is lost.
+/// A dirty chunk is held here even if the eviction policy drops it,
+/// so the unflushed data is never lost or read stale from the file.
+#[derive(Default)]
+pub(crate) struct WriteBack {
+    inner: Mutex<WriteBackInner>,
+}
+
+#[derive(Default)]
+struct WriteBackInner {
+    dirty: BTreeMap<CacheKey, Arc<Mutex<Chunk>>>,
+    lens: HashMap<u32, u64>, // map file id to the length including the unflushed data
+}
+
+impl WriteBack {
+    // Flush all the dirty chunks every interval, until the write-back is dropped
+    pub fn spawn_flusher(write_back: &Arc<WriteBack>, interval: Duration) {
+        let write_back: Weak<WriteBack> = Arc::downgrade(write_back);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match write_back.upgrade() {
+                // A failed chunk stays dirty and is retried by the next flush
+                Some(write_back) => {
+                    let _ = write_back.flush_all();
+                }
+                None => break,
+            }
+        });
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Mutex<Chunk>>> {
+        self.inner.lock().unwrap().dirty.get(key).cloned()
+    }
+
+    // The file length including the unflushed data, None if the file wasn't written
+    pub fn len(&self, file_id: u32) -> Option<u64> {
+        self.inner.lock().unwrap().lens.get(&file_id).copied()
+    }
+
+    // Called after the chunk is written, the write ends at the offset end of the file,
+    // the chunk must be unlocked, as the flushes lock the chunks with the dirty list locked,
+    // and its shard locked, so it isn't evicted before it's held here
+    pub fn mark_dirty(&self, key: CacheKey, chunk: &Arc<Mutex<Chunk>>, end: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dirty.insert(key, Arc::clone(chunk));
+        let len = inner.lens.entry(key.0).or_insert(0);
+        *len = (*len).max(end);
+    }
+
+    // Flush the dirty chunks of the file up to the index-th chunk,
+    // called before the chunk is evicted
+    pub fn flush_until(&self, key: CacheKey) -> io::Result<()> {
+        self.flush(CacheKey(key.0, 0)..=key)
+    }
+
+    pub fn flush_file(&self, file_id: u32) -> io::Result<()> {
+        self.flush(CacheKey(file_id, 0)..=CacheKey(file_id, usize::MAX))
+    }
+
+    pub fn flush_all(&self) -> io::Result<()> {
+        self.flush(..)
+    }
+
+    // Drop the dirty chunks of a removed file without writing them
+    pub fn discard_file(&self, file_id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        let range = CacheKey(file_id, 0)..=CacheKey(file_id, usize::MAX);
+        let keys: Vec<CacheKey> = inner.dirty.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            if let Some(chunk) = inner.dirty.remove(&key) {
+                chunk.lock().unwrap().discard();
+            }
+        }
+        inner.lens.remove(&file_id);
+    }
+
+    pub fn rename_file(&self, from: u32, to: u32) {
+        self.discard_file(to);
+
+        let mut inner = self.inner.lock().unwrap();
+        let range = CacheKey(from, 0)..=CacheKey(from, usize::MAX);
+        let keys: Vec<CacheKey> = inner.dirty.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            if let Some(chunk) = inner.dirty.remove(&key) {
+                inner.dirty.insert(CacheKey(to, key.1), chunk);
+            }
+        }
+        if let Some(len) = inner.lens.remove(&from) {
+            inner.lens.insert(to, len);
+        }
+    }
+
+    // Store the dirty chunks in the range in order, with the dirty list locked,
+    // so the concurrent flushes can't reorder the writes;
+    // the chunks after a failed one stay dirty
+    fn flush(&self, range: impl std::ops::RangeBounds<CacheKey>) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<CacheKey> = inner.dirty.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            inner.dirty[&key].lock().unwrap().sync()?;
+            inner.dirty.remove(&key);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WriteBack {
+    fn drop(&mut self) {
+        let _ = self.flush_all();
+    }
+}
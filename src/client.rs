@@ -0,0 +1,675 @@
+use crate::*;
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::Bound::Included;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The requests of a pipeline sent before their responses are read, at most
+const PIPELINE_WINDOW: usize = 256;
+const PIPELINE_WINDOW_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    // The timeout of sending a request and of reading each response, None waits forever
+    pub request_timeout: Option<Duration>,
+    // The idle connections kept for the later requests
+    pub max_idle: usize,
+    // The times a request is sent again over a new connection if the connection breaks
+    pub retries: usize,
+    // Json for the servers older than the binary protocol
+    pub protocol: Protocol,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(3),
+            request_timeout: Some(Duration::from_secs(10)),
+            max_idle: 8,
+            retries: 2,
+            protocol: Protocol::Binary,
+        }
+    }
+}
+
+/// `Client` talks to a CyKV server, and implements `KvEngine`,
+/// so the code using an embedded `CyStore` can use a remote store instead.
+/// The clones share a pool of connections, a request takes an idle connection
+/// or opens a new one, and puts it back when the response is read.
+/// A request failing with a broken connection is sent again over a new connection
+/// if it doesn't change the store, or if it couldn't be sent. A write broken after
+/// it was sent isn't sent again, as the server may have applied it, but the server
+/// may also have received a write whose sending failed, so a write is applied
+/// at least once, not exactly once, e.g. a `remove` sent again may fail with `KeyNotFound`.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+    addr: SocketAddr,
+    options: ClientOptions,
+    idle: Mutex<Vec<Connection>>,
+}
+
+type Responses = StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, Response>;
+
+enum Reader {
+    Json(Responses),
+    Binary {
+        reader: BufReader<TcpStream>,
+        negotiated: bool, // the preamble of the server was read
+    },
+}
+
+// The stream of a connection, counting the bytes written to it
+struct WriteStream {
+    stream: TcpStream,
+    written: u64,
+}
+
+impl Write for WriteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+struct Connection {
+    writer: BufWriter<WriteStream>,
+    reader: Reader,
+    id: u64, // the id of the last request
+    reused: bool,
+}
+
+impl Connection {
+    fn open(addr: &SocketAddr, options: &ClientOptions) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(addr, options.connect_timeout)?;
+        stream.set_read_timeout(options.request_timeout)?;
+        stream.set_write_timeout(options.request_timeout)?;
+        stream.set_nodelay(true)?;
+
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(WriteStream { stream, written: 0 });
+        let reader = match options.protocol {
+            Protocol::Json => Reader::Json(Deserializer::from_reader(reader).into_iter()),
+            // The preamble goes with the first request,
+            // and the answer of the server is read before the first response
+            Protocol::Binary => {
+                writer.write_all(PREAMBLE)?;
+                Reader::Binary {
+                    reader,
+                    negotiated: false,
+                }
+            }
+        };
+        Ok(Self {
+            writer,
+            reader,
+            id: 0,
+            reused: false,
+        })
+    }
+
+    // Whether the server closed the connection while it was idle,
+    // the data of a live idle connection is never ready to read
+    fn is_closed(&self) -> bool {
+        let stream = &self.writer.get_ref().stream;
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let mut buf = [0; 1];
+        let closed =
+            !matches!(stream.peek(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+        closed || stream.set_nonblocking(false).is_err()
+    }
+
+    fn send(&mut self, req: &Request) -> Result<u64> {
+        let id = self.write(req)?;
+        self.writer.flush()?;
+        Ok(id)
+    }
+
+    // Buffer a request, return its id
+    fn write(&mut self, req: &Request) -> Result<u64> {
+        self.id += 1;
+        match self.reader {
+            Reader::Json(_) => serde_json::to_writer(&mut self.writer, req)?,
+            Reader::Binary { .. } => self.writer.write_all(&encode_request(self.id, req)?)?,
+        }
+        Ok(self.id)
+    }
+
+    // Read the next response, which answers the request id,
+    // the JSON responses come in the order of the requests too, without the ids
+    fn receive(&mut self, id: u64) -> Result<Response> {
+        match &mut self.reader {
+            Reader::Json(responses) => match responses.next() {
+                Some(res) => Ok(res?),
+                None => Err(closed_error()),
+            },
+            Reader::Binary { reader, negotiated } => {
+                if !*negotiated {
+                    let mut preamble = [0; PREAMBLE.len()];
+                    reader.read_exact(&mut preamble)?;
+                    if preamble != PREAMBLE {
+                        return Err(CyKvError::Remote(WireError::new(
+                            ErrorCode::Unsupported,
+                            "the server doesn't speak this version of the binary protocol, \
+                             try the JSON protocol",
+                        )));
+                    }
+                    *negotiated = true;
+                }
+
+                let frame = read_frame(reader, MAX_RESPONSE_SIZE)?;
+                let (res_id, res) = decode_response(&frame)?;
+                if res_id != id {
+                    return Err(CyKvError::Remote(WireError::new(
+                        ErrorCode::Internal,
+                        format!("the response of request {} to request {}", res_id, id),
+                    )));
+                }
+                Ok(res)
+            }
+        }
+    }
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Client::with_options(addr, ClientOptions::default())
+    }
+
+    // Resolve the address and check the server is reachable
+    pub fn with_options(addr: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| CyKvError::InvalidArgument("no server address".to_owned()))?;
+        let conn = Connection::open(&addr, &options)?;
+
+        let client = Self {
+            inner: Arc::new(ClientInner {
+                addr,
+                options,
+                idle: Mutex::new(Vec::new()),
+            }),
+        };
+        client.put_back(conn);
+        Ok(client)
+    }
+
+    fn take(&self) -> Result<Connection> {
+        while let Some(mut conn) = self.inner.idle.lock().unwrap().pop() {
+            if conn.is_closed() {
+                continue;
+            }
+            conn.reused = true;
+            return Ok(conn);
+        }
+        Connection::open(&self.inner.addr, &self.inner.options)
+    }
+
+    fn put_back(&self, conn: Connection) {
+        let mut idle = self.inner.idle.lock().unwrap();
+        if idle.len() < self.inner.options.max_idle {
+            idle.push(conn);
+        }
+    }
+
+    // Send the request and read the first response over a connection,
+    // the connection is returned so the rest of a scan can be read from it
+    fn exchange(&self, req: &Request) -> Result<(Connection, u64, Response)> {
+        let mut attempts = 0;
+        loop {
+            let mut conn = self.take()?;
+            let res = match conn.send(req) {
+                Ok(id) => conn.receive(id).map(|res| (id, res)).map_err(|e| (e, true)),
+                Err(e) => Err((e, false)),
+            };
+            let (e, sent) = match res {
+                Ok((id, res)) => return Ok((conn, id, res)),
+                Err(err) => err,
+            };
+
+            // A broken idle connection hints the server closed the others too,
+            // e.g. it restarted, so the pool is emptied, and the attempt isn't counted
+            // as the next one uses a new connection
+            if conn.reused && is_broken(&e) {
+                self.inner.idle.lock().unwrap().clear();
+            }
+            if !should_retry(req, sent, &e)
+                || (!conn.reused && attempts == self.inner.options.retries)
+            {
+                // A timed out or broken connection is dropped, its late responses
+                // would be read as the responses of the next requests
+                return Err(e);
+            }
+            if !conn.reused {
+                attempts += 1;
+            }
+        }
+    }
+
+    // Queue requests to send at once, see Pipeline
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            client: self.clone(),
+            requests: Vec::new(),
+        }
+    }
+
+    fn request(&self, req: &Request) -> Result<Response> {
+        let (conn, _, res) = self.exchange(req)?;
+        self.put_back(conn);
+        match res {
+            Response::Err(err) => Err(remote_error(err)),
+            res => Ok(res),
+        }
+    }
+}
+
+// Whether the request can be sent again after the error, a write only if it failed
+// to be sent, once the response is awaited the server may have applied it
+fn should_retry(req: &Request, sent: bool, err: &CyKvError) -> bool {
+    is_broken(err) && (is_read_only(req) || !sent)
+}
+
+fn is_broken(err: &CyKvError) -> bool {
+    match err {
+        CyKvError::Io(err) => matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        ),
+        CyKvError::SerdeJson(err) => err.is_eof() || err.is_io(),
+        _ => false,
+    }
+}
+
+fn is_read_only(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::Get { .. }
+            | Request::Range { .. }
+            | Request::ScanPrefix { .. }
+            | Request::Keys { .. }
+            | Request::Count { .. }
+            | Request::Stats
+    )
+}
+
+fn closed_error() -> CyKvError {
+    CyKvError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the server closed the connection",
+    ))
+}
+
+// Map the error sent by the server back to the error of the engine
+fn remote_error(err: WireError) -> CyKvError {
+    match err.code {
+        ErrorCode::NotFound => CyKvError::KeyNotFound(err.message),
+        _ => CyKvError::Remote(err),
+    }
+}
+
+fn unexpected(res: Response) -> CyKvError {
+    CyKvError::Remote(WireError::new(
+        ErrorCode::Internal,
+        format!("unexpected response {:?}", res),
+    ))
+}
+
+impl KvEngine for Client {
+    type Range = ClientRange;
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { Key: key })? {
+            Response::Ok(value) => Ok(value),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn scan(&self, begin: String, end: String) -> Result<Vec<String>> {
+        if begin > end {
+            return Err(CyKvError::InvalidArgument("begin > end".to_owned()));
+        }
+
+        self.range((Included(begin), Included(end)), ScanOptions::default())
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect()
+    }
+
+    fn range<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> ClientRange {
+        let req = Request::Range {
+            Start: range.start_bound().cloned(),
+            End: range.end_bound().cloned(),
+            Limit: options.limit,
+            Reverse: options.reverse,
+            PageSize: None,
+        };
+
+        let mut pairs = ClientRange {
+            client: self.clone(),
+            conn: None,
+            id: 0,
+            page: VecDeque::new(),
+            error: None,
+        };
+        match self.exchange(&req) {
+            Ok((conn, id, res)) => {
+                pairs.conn = Some(conn);
+                pairs.id = id;
+                pairs.read_page(res);
+            }
+            Err(e) => pairs.error = Some(e),
+        }
+        pairs
+    }
+
+    fn keys<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> Result<Vec<String>> {
+        let req = Request::Keys {
+            Start: range.start_bound().cloned(),
+            End: range.end_bound().cloned(),
+            Limit: options.limit,
+            Reverse: options.reverse,
+            PageSize: None,
+        };
+
+        // The pages are read from the same connection until the last one
+        let (mut conn, id, mut res) = self.exchange(&req)?;
+        let mut keys = Vec::new();
+        loop {
+            match res {
+                Response::Keys {
+                    Keys: page,
+                    More: more,
+                } => {
+                    keys.extend(page);
+                    if !more {
+                        self.put_back(conn);
+                        return Ok(keys);
+                    }
+                }
+                Response::Err(err) => {
+                    self.put_back(conn);
+                    return Err(remote_error(err));
+                }
+                res => return Err(unexpected(res)),
+            }
+            res = conn.receive(id)?;
+        }
+    }
+
+    fn count<R: RangeBounds<String>>(&self, range: R) -> Result<u64> {
+        let req = Request::Count {
+            Start: range.start_bound().cloned(),
+            End: range.end_bound().cloned(),
+        };
+        match self.request(&req)? {
+            Response::Count(count) => Ok(count),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        match self.request(&Request::Set {
+            Key: key,
+            Value: value,
+        })? {
+            Response::Ok(old_value) => Ok(old_value),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<String> {
+        match self.request(&Request::Remove { Key: key })? {
+            Response::Ok(Some(old_value)) => Ok(old_value),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<u64> {
+        let req = Request::DeleteRange {
+            Start: range.start_bound().cloned(),
+            End: range.end_bound().cloned(),
+        };
+        match self.request(&req)? {
+            Response::Count(count) => Ok(count),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    // The writes are pipelined, and the server applies them as one batch,
+    // a failed sync fails each write the server applied with the Unsynced code
+    fn write_batch(&self, batch: Vec<WriteOp>) -> Result<BatchResults> {
+        let mut pipeline = self.pipeline();
+        for op in batch {
+            match op {
+                WriteOp::Set { key, value } => pipeline.set(key, value),
+                WriteOp::Remove { key } => pipeline.remove(key),
+            };
+        }
+        Ok(BatchResults {
+            results: pipeline.execute()?,
+            sync: Ok(()),
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        match self.request(&Request::Sync)? {
+            Response::Ok(_) => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        match self.request(&Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    fn resize_cache(&self, cache_bytes: u64) -> Result<()> {
+        match self.request(&Request::Resize {
+            CacheBytes: cache_bytes,
+        })? {
+            Response::Ok(_) => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+}
+
+/// `Pipeline` sends its requests over one connection without waiting for each response,
+/// so many small requests take a few round trips instead of one each.
+/// The server runs them in order, and applies the consecutive writes as one batch.
+pub struct Pipeline {
+    client: Client,
+    requests: Vec<Request>,
+}
+
+impl Pipeline {
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { Key: key });
+        self
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set {
+            Key: key,
+            Value: value,
+        });
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { Key: key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    // Send the queued requests, and return the result of each in order:
+    // the value for get, the previous value for set and remove.
+    // A broken connection fails the whole pipeline, the requests answered before may have run
+    pub fn execute(&mut self) -> Result<Vec<Result<Option<String>>>> {
+        let requests = mem::take(&mut self.requests);
+        let retries = self.client.inner.options.retries;
+        let mut attempts = 0;
+        loop {
+            let mut conn = self.client.take()?;
+            let written = conn.writer.get_ref().written;
+            let mut results = Vec::with_capacity(requests.len());
+            let e = match run_pipeline(&mut conn, &requests, &mut results) {
+                Ok(()) => {
+                    self.client.put_back(conn);
+                    return Ok(results);
+                }
+                Err(e) => e,
+            };
+
+            // As for a single request, if nothing was answered yet,
+            // and the writes only if none of the requests reached the server
+            let sent = conn.writer.get_ref().written > written;
+            if conn.reused && is_broken(&e) {
+                self.client.inner.idle.lock().unwrap().clear();
+            }
+            if !results.is_empty()
+                || !requests.iter().all(|req| should_retry(req, sent, &e))
+                || (!conn.reused && attempts == retries)
+            {
+                return Err(e);
+            }
+            if !conn.reused {
+                attempts += 1;
+            }
+        }
+    }
+}
+
+// The requests are sent in windows small enough for the buffers of the connection,
+// or else the server could block writing the responses no one reads yet,
+// and stop reading the requests the client is blocked writing
+fn run_pipeline(
+    conn: &mut Connection,
+    requests: &[Request],
+    results: &mut Vec<Result<Option<String>>>,
+) -> Result<()> {
+    let mut ids = Vec::new();
+    let mut bytes = 0;
+
+    for (i, req) in requests.iter().enumerate() {
+        ids.push(conn.write(req)?);
+        bytes += match req {
+            Request::Set {
+                Key: key,
+                Value: value,
+            } => key.len() + value.len(),
+            Request::Get { Key: key } | Request::Remove { Key: key } => key.len(),
+            _ => 0,
+        };
+        if ids.len() < PIPELINE_WINDOW && bytes < PIPELINE_WINDOW_BYTES && i + 1 < requests.len() {
+            continue;
+        }
+
+        conn.writer.flush()?;
+        for id in ids.drain(..) {
+            let res = match conn.receive(id)? {
+                Response::Ok(value) => Ok(value),
+                Response::Err(err) => Err(remote_error(err)),
+                res => return Err(unexpected(res)),
+            };
+            results.push(res);
+        }
+        bytes = 0;
+    }
+
+    Ok(())
+}
+
+/// `ClientRange` reads the pages of a scan from the server as it's iterated,
+/// the connection goes back to the pool after the last page,
+/// and is closed if the iterator is dropped before.
+pub struct ClientRange {
+    client: Client,
+    conn: Option<Connection>, // None after the last page
+    id: u64,                  // the id of the scan request
+    page: VecDeque<(String, String)>,
+    error: Option<CyKvError>,
+}
+
+impl ClientRange {
+    fn read_page(&mut self, res: Response) {
+        match res {
+            Response::Page {
+                Pairs: pairs,
+                More: more,
+            } => {
+                self.page.extend(pairs);
+                if !more {
+                    if let Some(conn) = self.conn.take() {
+                        self.client.put_back(conn);
+                    }
+                }
+            }
+            Response::Err(err) => {
+                self.error = Some(remote_error(err));
+                // The pages end with the error
+                if let Some(conn) = self.conn.take() {
+                    self.client.put_back(conn);
+                }
+            }
+            res => {
+                self.error = Some(unexpected(res));
+                self.conn = None;
+            }
+        }
+    }
+}
+
+impl Iterator for ClientRange {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.page.pop_front() {
+                return Some(Ok(pair));
+            }
+            if let Some(e) = self.error.take() {
+                self.conn = None;
+                return Some(Err(e));
+            }
+
+            let conn = self.conn.as_mut()?;
+            match conn.receive(self.id) {
+                Ok(res) => self.read_page(res),
+                Err(e) => {
+                    self.conn = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
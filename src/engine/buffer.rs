@@ -1,6 +1,6 @@
 // IO Buffer with offset
 
-use std::io::{self, Seek, Write, SeekFrom};
+use std::io::{self, Seek, Write};
 
 pub struct BufWriter<W: Write + Seek> {
     inner: W,
@@ -9,7 +9,7 @@ pub struct BufWriter<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriter<W> {
     pub fn new(mut inner: W) -> io::Result<Self> {
-	    let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(Self { inner, pos })
     }
 }
@@ -1,27 +1,34 @@
 use super::buffer::BufWriter;
+use super::reader::{decode_command, LogReader};
+use super::sparse_index::{SparseIndex, SPARSE_BLOCK_SIZE};
+use super::value_cache::ValueCache;
 use crate::cache::{Cache, CacheManager};
-use crate::engine::KvEngine;
-use crate::LruCacheManager;
+use crate::engine::{BatchResults, KvEngine, StoreStats};
 use crate::*;
 use serde::{Deserialize, Serialize};
-use std::cmp::max;
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{self, File, OpenOptions};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions, TryLockError};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::ops::Bound::Included;
+use std::mem;
+use std::ops::Bound::{self, Excluded, Included};
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
 // 32 MiB
-const COMPACT_THRESHOLD: u64 = 32 << 20;
+pub const DEFAULT_COMPACT_THRESHOLD: u64 = 32 << 20;
 const KEYDIR_PATH: &str = "keydir.json";
+const LOCK_PATH: &str = "LOCK";
+// The number of keydir entries a range iterator reads with one lock
+const RANGE_BATCH_SIZE: usize = 128;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct LogIndex {
-    id: u32,
-    command_pos: u64,
-    len: u64,
+    pub id: u32,
+    pub command_pos: u64,
+    pub len: u64,
 }
 
 impl LogIndex {
@@ -36,107 +43,226 @@ impl LogIndex {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    // Range tombstone, removes all the keys in the range
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+}
+
+/// `Durability` decides when the writes are synced to the disk,
+/// a write which isn't synced yet may be lost if the machine crashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // The writes reach the disk when the system, or the cache, writes them back
+    Buffered,
+    // Every write is synced before it returns
+    Sync,
+    // The writes are synced every interval
+    Periodic(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreOptions {
+    // The memory budget of the decoded values cached by get(), 0 disables the cache
+    pub value_cache_bytes: u64,
+    // Compact the logs when the overwritten and removed commands take this many bytes
+    pub compact_threshold: u64,
+    pub durability: Durability,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            value_cache_bytes: 0,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+            durability: Durability::Buffered,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct CyStore {
-    dir: Arc<PathBuf>, // The directory of the cykv stores data.
-
     keydir: Arc<RwLock<BTreeMap<String, LogIndex>>>, // Map key to log index.
-    log_id: Arc<u32>,
+    sparse_indexes: Arc<RwLock<HashMap<u32, SparseIndex>>>, // Indexes of the compacted logs.
 
-    cache_manager: Arc<dyn CacheManager>,
+    reader: Arc<LogReader>, // Shared by the readers and the writer.
+    value_cache: Option<Arc<ValueCache>>,
     writer: Arc<Mutex<CyStoreWriter>>,
+    // Held until the last clone is dropped, after the writer flushed its data
+    _lock: Arc<File>,
 }
 
 impl CyStore {
     pub fn open(dir: PathBuf, cache_manager: Box<dyn CacheManager>) -> Result<Self> {
+        CyStore::open_with_options(dir, cache_manager, StoreOptions::default())
+    }
+
+    pub fn open_with_options(
+        dir: PathBuf,
+        cache_manager: Box<dyn CacheManager>,
+        options: StoreOptions,
+    ) -> Result<Self> {
+        let lock = CyStore::lock(&dir)?;
         let mut keydir = BTreeMap::new();
-        let mut log_id = 0;
+        let mut sparse_indexes = HashMap::new();
         let mut uncompacted = 0;
 
-        for entry in dir.read_dir()? {
-            let entry = entry?;
-            if let Some(ext) = entry.path().extension() {
-                if ext == "log" {
-                    log_id = max(
-                        log_id,
-                        CyStore::read_log(entry.path().as_path(), &mut keydir, &mut uncompacted)?,
-                    );
-                }
+        // Replay the logs from the oldest to the newest,
+        // so the later commands overwrite the earlier ones
+        let log_ids = log_ids(&dir)?;
+        for &id in &log_ids {
+            CyStore::read_log(
+                log_path(&dir, id).as_path(),
+                id,
+                &mut keydir,
+                &mut uncompacted,
+            )?;
+
+            let index_path = index_path(&dir, id);
+            if index_path.exists() {
+                sparse_indexes.insert(id, SparseIndex::load(index_path.as_path())?);
             }
         }
+        let log_id = log_ids.last().copied().unwrap_or(0);
 
         let keydir = Arc::new(RwLock::new(keydir));
+        let sparse_indexes = Arc::new(RwLock::new(sparse_indexes));
         let log_id = Arc::new(log_id + 1);
 
         let cache = cache_manager.open(log_path(&dir, *log_id).as_path(), *log_id);
         let dir = Arc::new(dir);
-        let cache_manager = Arc::from(cache_manager);
+        let reader = Arc::new(LogReader::new(Arc::clone(&dir), Arc::from(cache_manager)));
+        let value_cache = match options.value_cache_bytes {
+            0 => None,
+            bytes => Some(Arc::new(ValueCache::new(bytes))),
+        };
         let writer = CyStoreWriter {
-            dir: Arc::clone(&dir),
-            cache_manager: Arc::clone(&cache_manager),
+            dir,
+            reader: Arc::clone(&reader),
+            value_cache: value_cache.clone(),
             keydir: Arc::clone(&keydir),
+            sparse_indexes: Arc::clone(&sparse_indexes),
             log_id: Arc::clone(&log_id),
             uncompacted,
+            compact_threshold: options.compact_threshold,
+            sync_writes: options.durability == Durability::Sync,
             writer: cache,
         };
+        let writer = Arc::new(Mutex::new(writer));
+        if let Durability::Periodic(interval) = options.durability {
+            CyStore::spawn_syncer(&writer, interval);
+        }
 
         Ok(Self {
-            dir,
             keydir,
-            log_id,
-            cache_manager,
-            writer: Arc::new(Mutex::new(writer)),
+            sparse_indexes,
+            reader,
+            value_cache,
+            writer,
+            _lock: Arc::new(lock),
         })
     }
 
-    fn log_path(&self, id: u32) -> PathBuf {
-        self.dir.join(format!("{}.log", id))
+    // Lock the directory, so no other store, in this process or another, writes the same logs.
+    // The system releases the lock if the process dies
+    fn lock(dir: &Path) -> Result<File> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(dir.join(LOCK_PATH))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(lock),
+            Err(TryLockError::WouldBlock) => Err(CyKvError::Locked(dir.display().to_string())),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
     }
 
-    fn read_command(&self, log_index: &LogIndex) -> Result<Command> {
-        let mut cache = self
-            .cache_manager
-            .open(self.log_path(log_index.id).as_path(), log_index.id);
-
-        cache.seek(SeekFrom::Start(log_index.command_pos))?;
+    // Sync the active log every interval, until the store is dropped
+    fn spawn_syncer(writer: &Arc<Mutex<CyStoreWriter>>, interval: Duration) {
+        let writer: Weak<Mutex<CyStoreWriter>> = Arc::downgrade(writer);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match writer.upgrade() {
+                // A failed sync is retried by the next one
+                Some(writer) => {
+                    let _ = writer.lock().unwrap().writer.flush();
+                }
+                None => break,
+            }
+        });
+    }
 
-        let cmd: Command = bson::from_document(bson::Document::from_reader(&mut *cache)?)?;
+    // Read the values of the commands lying back to back in a log,
+    // with one sequential read
+    fn read_run(&self, run: &[&LogIndex]) -> Result<Vec<String>> {
+        let id = run[0].id;
+        let start = run
+            .iter()
+            .map(|log_index| log_index.command_pos)
+            .min()
+            .unwrap();
+        let end = run
+            .iter()
+            .map(|log_index| log_index.command_pos + log_index.len)
+            .max()
+            .unwrap();
+
+        let buf = self.reader.read(id, start, end - start)?;
+
+        let mut values = Vec::with_capacity(run.len());
+        for log_index in run {
+            let offset = (log_index.command_pos - start) as usize;
+            match decode_command(&buf[offset..offset + log_index.len as usize])? {
+                Command::Set { key: _, value } => values.push(value),
+                _ => return Err(CyKvError::Internal),
+            }
+        }
 
-        Ok(cmd)
+        Ok(values)
     }
 
     fn read_log(
         path: &Path,
+        log_id: u32,
         keydir: &mut BTreeMap<String, LogIndex>,
         uncompacted: &mut u64,
-    ) -> Result<u32> {
+    ) -> Result<()> {
         let mut log_file = File::open(path)?;
-        let log_id = path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .trim_end_matches(".log")
-            .parse()
-            .map_err(|_| CyKvError::Internal)?;
 
         let mut pos = 0;
         let len = log_file.metadata()?.len();
 
         while pos < len {
+            // A crash may leave the last command partly written, which is never acknowledged,
+            // as the cache writes back the chunks of a log in order
+            if len - pos < 4 {
+                break;
+            }
+            let mut doc_len = [0; 4];
+            log_file.read_exact(&mut doc_len)?;
+            log_file.seek(SeekFrom::Start(pos))?;
+            if u32::from_le_bytes(doc_len) as u64 > len - pos {
+                break;
+            }
+
             let command: Command =
                 bson::from_document(bson::Document::from_reader(&mut log_file)?)?;
-            let new_pos = log_file.seek(SeekFrom::Current(0))?;
+            let new_pos = log_file.stream_position()?;
 
             let log_index = LogIndex::new(log_id, pos, new_pos - pos);
             pos = new_pos;
 
             match command {
-                Command::Set { key, value } => {
+                Command::Set { key, value: _ } => {
                     if let Some(log_index) = keydir.insert(key, log_index) {
                         *uncompacted += log_index.len;
                     }
@@ -146,166 +272,482 @@ impl CyStore {
                         *uncompacted += log_index.len;
                     }
                 }
+                Command::RemoveRange { start, end } => {
+                    for removed in remove_range(keydir, start, end) {
+                        *uncompacted += removed.len;
+                    }
+                    *uncompacted += log_index.len;
+                }
             }
         }
 
-        Ok(log_id)
+        Ok(())
     }
 }
 
 impl KvEngine for CyStore {
+    type Range = CyRange;
+
     fn get(&self, key: String) -> Result<Option<String>> {
         match self.keydir.read().unwrap().get(&key) {
             Some(log_index) => {
-                let cmd: Command = self.read_command(log_index)?;
+                let value_cache = match &self.value_cache {
+                    Some(value_cache) => value_cache,
+                    None => return self.reader.read_value(log_index),
+                };
+                if let Some(value) = value_cache.get(log_index) {
+                    return Ok(Some(value));
+                }
 
-                if let Command::Set { key: _, value } = cmd {
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
+                // Insert with the keydir locked, so a compaction can't remove the file
+                // and reuse its id in between
+                let value = self.reader.read_value(log_index)?;
+                if let Some(value) = &value {
+                    value_cache.insert(log_index, value.clone());
                 }
+                Ok(value)
             }
-
             None => Ok(None),
         }
     }
 
     fn scan(&self, begin: String, end: String) -> Result<Vec<String>> {
         if begin > end {
-            return Err(CyKvError::KeyNotFound("begin > end".to_owned()));
+            return Err(CyKvError::InvalidArgument("begin > end".to_owned()));
+        }
+
+        self.range((Included(begin), Included(end)), ScanOptions::default())
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect()
+    }
+
+    fn range<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> CyRange {
+        CyRange {
+            store: self.clone(),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            reverse: options.reverse,
+            remaining: options.limit,
+            batch: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    fn keys<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> Result<Vec<String>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
         }
 
         let keydir = self.keydir.read().unwrap();
-        let mut values = Vec::new();
-        for (_, log_index) in keydir.range((Included(begin), Included(end))) {
-            let cmd = self.read_command(log_index)?;
+        let keys = keydir.range((start, end)).map(|(key, _)| key.to_string());
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let keys = if options.reverse {
+            keys.rev().take(limit).collect()
+        } else {
+            keys.take(limit).collect()
+        };
 
-            if let Command::Set { key: _, value } = cmd {
-                values.push(value)
-            }
+        Ok(keys)
+    }
+
+    fn count<R: RangeBounds<String>>(&self, range: R) -> Result<u64> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&start, &end) {
+            return Ok(0);
         }
 
-        Ok(values)
+        Ok(self.keydir.read().unwrap().range((start, end)).count() as u64)
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
         self.writer.lock().unwrap().set(key, value)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<String> {
         self.writer.lock().unwrap().remove(key)
     }
+
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<u64> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.writer.lock().unwrap().delete_range(start, end)
+    }
+
+    fn write_batch(&self, batch: Vec<WriteOp>) -> Result<BatchResults> {
+        Ok(self.writer.lock().unwrap().write_batch(batch))
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        // The dirty chunks the cache keeps for the other logs
+        self.reader.flush_cache()
+    }
+
+    fn resize_cache(&self, cache_bytes: u64) -> Result<()> {
+        self.reader.resize_cache(cache_bytes)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let (active_log_id, uncompacted_bytes) = {
+            let writer = self.writer.lock().unwrap();
+            (*writer.log_id, writer.uncompacted)
+        };
+
+        Ok(StoreStats {
+            keys: self.keydir.read().unwrap().len() as u64,
+            active_log_id,
+            uncompacted_bytes,
+            cache: self.reader.cache_stats(),
+            value_cache: self
+                .value_cache
+                .as_ref()
+                .map(|value_cache| value_cache.stats()),
+        })
+    }
+}
+
+// Whether the commands of the two keys in a log are in the same block,
+// logs without a sparse index are split into blocks evenly by position
+fn same_block(
+    sparse_index: Option<&SparseIndex>,
+    (a_key, a): (&str, &LogIndex),
+    (b_key, b): (&str, &LogIndex),
+) -> bool {
+    match sparse_index {
+        Some(sparse_index) => sparse_index.block_of(a_key) == sparse_index.block_of(b_key),
+        None => a.command_pos / SPARSE_BLOCK_SIZE == b.command_pos / SPARSE_BLOCK_SIZE,
+    }
+}
+
+// Remove all the keys in the range from the keydir
+// return the indexes of the removed keys
+fn remove_range(
+    keydir: &mut BTreeMap<String, LogIndex>,
+    start: Bound<String>,
+    end: Bound<String>,
+) -> Vec<LogIndex> {
+    if is_empty_range(&start, &end) {
+        return Vec::new();
+    }
+
+    let keys: Vec<String> = keydir
+        .range((start, end))
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.iter().filter_map(|key| keydir.remove(key)).collect()
+}
+
+/// Iterator over the key-value pairs of a range, returned by `CyStore::range()`
+/// the keydir is read in batches of `RANGE_BATCH_SIZE` entries,
+/// and the lock is released between batches, so a long scan doesn't block writers.
+/// A pair written after the iterator has passed its key is not returned.
+pub struct CyRange {
+    store: CyStore,
+    // The bounds of the keys not returned yet
+    start: Bound<String>,
+    end: Bound<String>,
+    reverse: bool,
+    remaining: Option<usize>,
+    batch: VecDeque<Result<(String, String)>>,
+    exhausted: bool,
+}
+
+impl CyRange {
+    fn fill_batch(&mut self) {
+        let size = match self.remaining {
+            Some(remaining) => remaining.min(RANGE_BATCH_SIZE),
+            None => RANGE_BATCH_SIZE,
+        };
+        if size == 0 || is_empty_range(&self.start, &self.end) {
+            self.exhausted = true;
+            return;
+        }
+
+        let keydir = self.store.keydir.read().unwrap();
+        let entries = keydir.range((self.start.clone(), self.end.clone()));
+        let entries: Vec<(&String, &LogIndex)> = if self.reverse {
+            entries.rev().take(size).collect()
+        } else {
+            entries.take(size).collect()
+        };
+
+        if entries.len() < size {
+            self.exhausted = true;
+        }
+        if let Some((key, _)) = entries.last() {
+            if self.reverse {
+                self.end = Excluded(key.to_string());
+            } else {
+                self.start = Excluded(key.to_string());
+            }
+        }
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= entries.len();
+        }
+
+        // Commands lying back to back in the same block are read together,
+        // in a compacted log these are the neighbouring keys
+        let sparse_indexes = self.store.sparse_indexes.read().unwrap();
+        let mut begin = 0;
+        while begin < entries.len() {
+            let (first_key, first) = entries[begin];
+            let sparse_index = sparse_indexes.get(&first.id);
+            let (mut start, mut end) = (first.command_pos, first.command_pos + first.len);
+            let mut next = begin + 1;
+            while next < entries.len() {
+                let (key, log_index) = entries[next];
+                let contiguous = if self.reverse {
+                    log_index.command_pos + log_index.len == start
+                } else {
+                    log_index.command_pos == end
+                };
+                if log_index.id != first.id
+                    || !contiguous
+                    || !same_block(sparse_index, (first_key, first), (key, log_index))
+                {
+                    break;
+                }
+
+                start = start.min(log_index.command_pos);
+                end = end.max(log_index.command_pos + log_index.len);
+                next += 1;
+            }
+
+            let run: Vec<&LogIndex> = entries[begin..next].iter().map(|(_, v)| *v).collect();
+            match self.store.read_run(&run) {
+                Ok(values) => {
+                    for ((key, _), value) in entries[begin..next].iter().zip(values) {
+                        self.batch.push_back(Ok((key.to_string(), value)));
+                    }
+                }
+                Err(e) => {
+                    self.exhausted = true;
+                    self.batch.push_back(Err(e));
+                    break;
+                }
+            }
+            begin = next;
+        }
+    }
+}
+
+impl Iterator for CyRange {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.exhausted {
+            self.fill_batch();
+        }
+
+        self.batch.pop_front()
+    }
 }
 
 struct CyStoreWriter {
     dir: Arc<PathBuf>,
-    cache_manager: Arc<dyn CacheManager>,
+    reader: Arc<LogReader>,
+    value_cache: Option<Arc<ValueCache>>,
     keydir: Arc<RwLock<BTreeMap<String, LogIndex>>>,
+    sparse_indexes: Arc<RwLock<HashMap<u32, SparseIndex>>>,
     log_id: Arc<u32>,
     uncompacted: u64,
+    compact_threshold: u64,
+    sync_writes: bool,      // sync the log after each command
     writer: Box<dyn Cache>, // log file writer
 }
 
 impl CyStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        // Only this writer changes the keydir, so the displaced value is read before
+        // anything is written, and a failed read leaves the store unchanged
+        let old_index = self.keydir.read().unwrap().get(&key).cloned();
+        let old_value = match &old_index {
+            Some(log_index) => Some(self.read_value(log_index)?),
+            None => None,
+        };
+
         let cmd = Command::Set {
             key: key.clone(),
             value,
         };
-
         let log_index = self.append_command(cmd)?;
-        if let Some(log_index) = self.keydir.write().unwrap().insert(key, log_index) {
+        self.keydir.write().unwrap().insert(key, log_index);
+
+        // The displaced command stays on disk until the next compaction,
+        // which only this writer can trigger
+        if let Some(log_index) = old_index {
             self.uncompacted += log_index.len;
+            self.invalidate(&log_index);
         }
 
-        if self.uncompacted >= COMPACT_THRESHOLD {
+        if self.uncompacted >= self.compact_threshold {
             self.compact()?;
         }
 
-        Ok(())
+        Ok(old_value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<String> {
+        let old_index = self
+            .keydir
+            .read()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| CyKvError::KeyNotFound(key.clone()))?;
+        let old_value = self.read_value(&old_index)?;
+
+        let cmd = Command::Remove { key: key.clone() };
+        self.uncompacted += self.append_command(cmd)?.len;
+        self.keydir.write().unwrap().remove(&key);
+        self.uncompacted += old_index.len;
+        self.invalidate(&old_index);
+
+        if self.uncompacted >= self.compact_threshold {
+            self.compact()?;
+        }
+
+        Ok(old_value)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.keydir.write().unwrap().remove(&key) {
-            Some(log_index) => self.uncompacted += log_index.len,
-            None => return Err(CyKvError::KeyNotFound(key)),
+    fn delete_range(&mut self, start: Bound<String>, end: Bound<String>) -> Result<u64> {
+        if is_empty_range(&start, &end) {
+            return Ok(0);
+        }
+        // Only this writer changes the keydir, the keys stay until the tombstone is appended
+        let keys: Vec<String> = self
+            .keydir
+            .read()
+            .unwrap()
+            .range((start.clone(), end.clone()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if keys.is_empty() {
+            return Ok(0);
         }
 
-        let cmd = Command::Remove { key };
+        let cmd = Command::RemoveRange { start, end };
         self.uncompacted += self.append_command(cmd)?.len;
 
-        if self.uncompacted >= COMPACT_THRESHOLD {
+        let removed: Vec<LogIndex> = {
+            let mut keydir = self.keydir.write().unwrap();
+            keys.iter().filter_map(|key| keydir.remove(key)).collect()
+        };
+        for log_index in &removed {
+            self.uncompacted += log_index.len;
+            self.invalidate(log_index);
+        }
+
+        if self.uncompacted >= self.compact_threshold {
             self.compact()?;
         }
 
-        Ok(())
+        Ok(removed.len() as u64)
+    }
+
+    // The log is synced once after the batch, instead of after each command
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> BatchResults {
+        let sync_writes = mem::replace(&mut self.sync_writes, false);
+        let results = batch
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set { key, value } => self.set(key, value),
+                WriteOp::Remove { key } => self.remove(key).map(Some),
+            })
+            .collect();
+        self.sync_writes = sync_writes;
+
+        let sync = if sync_writes {
+            self.writer.flush().map_err(CyKvError::from)
+        } else {
+            Ok(())
+        };
+        BatchResults { results, sync }
+    }
+
+    // Read the value of a command about to be displaced from the keydir
+    fn read_value(&self, log_index: &LogIndex) -> Result<String> {
+        if let Some(value_cache) = &self.value_cache {
+            if let Some(value) = value_cache.get(log_index) {
+                return Ok(value);
+            }
+        }
+
+        // The keydir only points at Set commands
+        self.reader
+            .read_value(log_index)?
+            .ok_or(CyKvError::Internal)
+    }
+
+    // Drop the value of a displaced command from the value cache
+    fn invalidate(&self, log_index: &LogIndex) {
+        if let Some(value_cache) = &self.value_cache {
+            value_cache.invalidate(log_index);
+        }
     }
 
     fn append_command(&mut self, cmd: Command) -> Result<LogIndex> {
         let pos = self.writer.offset();
         bson::to_document(&cmd)?.to_writer(&mut *self.writer)?;
         let len = self.writer.offset() - pos;
+        if self.sync_writes {
+            self.writer.flush()?;
+        }
 
         Ok(LogIndex::new(*self.log_id, pos, len))
     }
 
-    fn read_command(&self, log_index: &LogIndex) -> Result<Command> {
-        let mut cache = self.cache_manager.open(
-            utils::log_path(self.dir.as_path(), log_index.id).as_path(),
-            log_index.id,
-        );
-        cache.seek(SeekFrom::Start(log_index.command_pos))?;
-
-        let cmd: Command = bson::from_document(bson::Document::from_reader(&mut *cache)?)?;
-
-        Ok(cmd)
-    }
-
     fn compact(&mut self) -> Result<()> {
+        // The compaction output takes the id just below the active log,
+        // it is written to a temporary id first and renamed at the end
         let compact_log_id = *self.log_id + 1;
+        let target_log_id = *self.log_id - 1;
         let compaction_file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(utils::log_path(self.dir.as_path(), compact_log_id).as_path())?;
         let mut writer = BufWriter::new(compaction_file)?;
+        let mut sparse_index = SparseIndex::default();
 
+        // Hold the keydir until the files are in place,
+        // so no reader could see an index to a removed file.
+        // The keydir is iterated in key order, so the output is sorted
         let mut keydir = self.keydir.write().unwrap();
-        for (_, log_index) in keydir.iter_mut() {
-            let cmd = self.read_command(log_index)?;
+        for (key, log_index) in keydir.iter_mut() {
+            let cmd = self.reader.read_command(log_index)?;
             let pos = writer.pos;
             bson::to_document(&cmd)?.to_writer(&mut writer)?;
+            sparse_index.push(key, pos);
 
-            log_index.id = compact_log_id;
+            log_index.id = target_log_id;
             log_index.command_pos = pos;
             log_index.len = writer.pos - pos;
         }
-        
+        drop(writer);
+        sparse_index.store(index_path(self.dir.as_path(), compact_log_id).as_path())?;
+
         let keydir_persister = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(self.dir.as_path().join(KEYDIR_PATH))?;
-        serde_json::to_writer(keydir_persister,&*keydir)?;
-        
-        drop(keydir);
-        drop(writer);
+        serde_json::to_writer(keydir_persister, &*keydir)?;
+
+        // Remove old log files and their indexes
+        let mut sparse_indexes = self.sparse_indexes.write().unwrap();
+        for log_id in log_ids(self.dir.as_path())? {
+            if log_id < *self.log_id {
+                // Windows can't remove a file while it's open or mapped
+                self.reader.retire(log_id);
+                fs::remove_file(log_path(self.dir.as_path(), log_id))?;
+                if let Some(value_cache) = &self.value_cache {
+                    value_cache.invalidate_file(log_id);
+                }
 
-        // Remove old log files
-        for entry in self.dir.read_dir()? {
-            let entry = entry?;
-            if let Some(ext) = entry.path().extension() {
-                if ext == "log" {
-                    let log_id: u32 = entry
-                        .file_name()
-                        .to_str()
-                        .unwrap()
-                        .trim_end_matches(".log")
-                        .parse()
-                        .map_err(|_| CyKvError::Internal)?;
-
-                    if log_id < *self.log_id {
-                        fs::remove_file(entry.path().as_path())?;
-                    }
+                if sparse_indexes.remove(&log_id).is_some() {
+                    fs::remove_file(index_path(self.dir.as_path(), log_id))?;
                 }
             }
         }
@@ -314,8 +756,14 @@ impl CyStoreWriter {
         // Rename the compaction file
         fs::rename(
             log_path(self.dir.as_path(), compact_log_id),
-            log_path(self.dir.as_path(), *self.log_id - 1),
+            log_path(self.dir.as_path(), target_log_id),
+        )?;
+        self.reader.rename(compact_log_id, target_log_id);
+        fs::rename(
+            index_path(self.dir.as_path(), compact_log_id),
+            index_path(self.dir.as_path(), target_log_id),
         )?;
+        sparse_indexes.insert(target_log_id, sparse_index);
 
         Ok(())
     }
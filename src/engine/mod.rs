@@ -1,22 +1,124 @@
 mod buffer;
 mod cykv;
+mod reader;
+mod sparse_index;
+mod value_cache;
 
 pub use cykv::*;
+pub use value_cache::ValueCacheStats;
 
+use crate::cache::CacheStats;
 use crate::*;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeBounds;
 
 pub trait KvEngine: Clone + Send + 'static {
+    type Range: Iterator<Item = Result<(String, String)>>;
+
     // Get the value
     fn get(&self, key: String) -> Result<Option<String>>;
 
     // Scan values
     fn scan(&self, begin: String, end: String) -> Result<Vec<String>>;
 
+    // Iterate the key-value pairs in the range, in key order
+    fn range<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> Self::Range;
+
+    // Iterate the key-value pairs whose keys start with the prefix
+    fn scan_prefix(&self, prefix: String, options: ScanOptions) -> Self::Range {
+        self.range(prefix_range(prefix), options)
+    }
+
+    // List the keys in the range, without reading the values
+    fn keys<R: RangeBounds<String>>(&self, range: R, options: ScanOptions) -> Result<Vec<String>>;
+
+    // Count the keys in the range, without reading the values
+    fn count<R: RangeBounds<String>>(&self, range: R) -> Result<u64>;
+
     // Set the value
-    // return the previous value
-    fn set(&self, key: String, value: String) -> Result<()>;
+    // return the previous value, or None if the key doesn't exist
+    fn set(&self, key: String, value: String) -> Result<Option<String>>;
 
     // Remove the key-value pair
-    // return the removed value
-    fn remove(&self, key: String) -> Result<()>;
+    // return the removed value, or KeyNotFound if the key doesn't exist
+    fn remove(&self, key: String) -> Result<String>;
+
+    // Remove all the key-value pairs in the range with a single range tombstone
+    // return the number of the removed keys
+    fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<u64>;
+
+    // Apply the writes in order, return the previous value of each key,
+    // a Remove of a missing key fails alone. The store holds its writer once for the batch,
+    // and syncs once at the end if every write is synced
+    fn write_batch(&self, batch: Vec<WriteOp>) -> Result<BatchResults> {
+        let results = batch
+            .into_iter()
+            .map(|op| match op {
+                WriteOp::Set { key, value } => self.set(key, value),
+                WriteOp::Remove { key } => self.remove(key).map(Some),
+            })
+            .collect();
+        Ok(BatchResults {
+            results,
+            sync: Ok(()),
+        })
+    }
+
+    // Persist the written data, including the data buffered by the cache
+    fn sync(&self) -> Result<()>;
+
+    // Report the statistics of the store and its caches
+    fn stats(&self) -> Result<StoreStats>;
+
+    // Change the memory budget of the cache manager
+    fn resize_cache(&self, cache_bytes: u64) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    // Iterate from the largest key to the smallest one
+    pub reverse: bool,
+    // Return at most limit pairs
+    pub limit: Option<usize>,
+}
+
+impl ScanOptions {
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// A write of `KvEngine::write_batch()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// The outcome of `KvEngine::write_batch()`, the result of each write in order,
+/// and the result of the sync after them. The writes stay applied if the sync fails,
+/// but a crash may lose them.
+#[derive(Debug)]
+pub struct BatchResults {
+    pub results: Vec<Result<Option<String>>>,
+    pub sync: Result<()>,
+}
+
+/// Statistics of a store, see `KvEngine::stats()`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub keys: u64,
+    // The log the writer appends to
+    pub active_log_id: u32,
+    // The bytes of the overwritten and removed commands, reclaimed by the next compaction
+    pub uncompacted_bytes: u64,
+    pub cache: CacheStats,
+    // None if the value cache is disabled
+    pub value_cache: Option<ValueCacheStats>,
 }
@@ -0,0 +1,101 @@
+// Shared reader of the log files
+
+use super::cykv::{Command, LogIndex};
+use crate::cache::{CacheManager, CacheStats};
+use crate::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// `LogReader` reads commands from the log files of a store.
+/// If the cache manager caches reads, the reads go through the cache,
+/// otherwise it keeps an open read-only handle per log file,
+/// and reads with `pread`, so the concurrent readers share the handles without seeking.
+pub(crate) struct LogReader {
+    dir: Arc<PathBuf>,
+    cache_manager: Arc<dyn CacheManager>,
+    handles: RwLock<HashMap<u32, Arc<File>>>, // map log id to the read-only handle
+}
+
+impl LogReader {
+    pub fn new(dir: Arc<PathBuf>, cache_manager: Arc<dyn CacheManager>) -> Self {
+        Self {
+            dir,
+            cache_manager,
+            handles: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Read len bytes at pos of the log
+    pub fn read(&self, id: u32, pos: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len as usize];
+
+        if self.cache_manager.caches_reads() {
+            let mut cache = self
+                .cache_manager
+                .open(log_path(self.dir.as_path(), id).as_path(), id);
+            cache.seek(SeekFrom::Start(pos))?;
+            cache.read_exact(&mut buf)?;
+        } else {
+            let file = self.handle(id)?;
+            read_exact_at(&file, &mut buf, pos)?;
+        }
+
+        Ok(buf)
+    }
+
+    pub fn read_command(&self, log_index: &LogIndex) -> Result<Command> {
+        let buf = self.read(log_index.id, log_index.command_pos, log_index.len)?;
+        decode_command(&buf)
+    }
+
+    pub fn read_value(&self, log_index: &LogIndex) -> Result<Option<String>> {
+        match self.read_command(log_index)? {
+            Command::Set { key: _, value } => Ok(Some(value)),
+            Command::Remove { .. } | Command::RemoveRange { .. } => Ok(None),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_manager.stats()
+    }
+
+    pub fn flush_cache(&self) -> Result<()> {
+        Ok(self.cache_manager.flush()?)
+    }
+
+    pub fn resize_cache(&self, cache_bytes: u64) -> Result<()> {
+        Ok(self.cache_manager.resize(cache_bytes)?)
+    }
+
+    // Close the handle and drop the cached data of a log,
+    // called when the log is removed by compaction
+    pub fn retire(&self, id: u32) {
+        self.handles.write().unwrap().remove(&id);
+        self.cache_manager.evict_file(id);
+    }
+
+    // Called when compaction renames its output to replace a log
+    pub fn rename(&self, from: u32, to: u32) {
+        let mut handles = self.handles.write().unwrap();
+        handles.remove(&from);
+        handles.remove(&to);
+        self.cache_manager.rename_file(from, to);
+    }
+
+    fn handle(&self, id: u32) -> Result<Arc<File>> {
+        if let Some(file) = self.handles.read().unwrap().get(&id) {
+            return Ok(Arc::clone(file));
+        }
+
+        let file = Arc::new(File::open(log_path(self.dir.as_path(), id))?);
+        let mut handles = self.handles.write().unwrap();
+        Ok(Arc::clone(handles.entry(id).or_insert(file)))
+    }
+}
+
+pub(crate) fn decode_command(mut buf: &[u8]) -> Result<Command> {
+    Ok(bson::from_document(bson::Document::from_reader(&mut buf)?)?)
+}
@@ -0,0 +1,52 @@
+// Sparse key-range index of a compacted log
+
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+// 64 KiB
+pub(crate) const SPARSE_BLOCK_SIZE: u64 = 64 << 10;
+
+/// The compaction output is sorted by key,
+/// it's divided into blocks of about `SPARSE_BLOCK_SIZE` bytes,
+/// and a block never splits a command.
+/// The index records the first key and the position of each block.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct SparseIndex {
+    blocks: Vec<(String, u64)>,
+}
+
+impl SparseIndex {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        serde_json::to_writer(&file, self)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Called with the commands in key order,
+    // starts a new block if the current one is full
+    pub fn push(&mut self, key: &str, pos: u64) {
+        match self.blocks.last() {
+            Some((_, block_pos)) if pos - block_pos < SPARSE_BLOCK_SIZE => {}
+            _ => self.blocks.push((key.to_owned(), pos)),
+        }
+    }
+
+    // The index of the block containing the command of the key,
+    // the keys of the log are sorted, so it's found by the first keys of the blocks
+    pub fn block_of(&self, key: &str) -> usize {
+        self.blocks
+            .partition_point(|(first_key, _)| first_key.as_str() <= key)
+            .saturating_sub(1)
+    }
+}
@@ -0,0 +1,126 @@
+// Cache of the decoded values
+
+use super::cykv::LogIndex;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+// The estimated memory of an entry besides the value
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// `ValueCache` caches the decoded values by the position of their commands,
+/// the least recently used values are evicted to keep the total size within the budget.
+/// A command never moves, so an entry only becomes stale when the key is overwritten
+/// or removed, or the log file is removed by compaction.
+pub(crate) struct ValueCache {
+    capacity: u64,
+    inner: Mutex<ValueCacheInner>,
+}
+
+struct ValueCacheInner {
+    values: lru::LruCache<(u32, u64), String>, // map (file id, command pos) to the value
+    size: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// Statistics of the value cache of a store
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValueCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Values dropped to keep the cache within its budget
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(ValueCacheInner {
+                values: lru::LruCache::unbounded(),
+                size: 0,
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, log_index: &LogIndex) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let value = inner
+            .values
+            .get(&(log_index.id, log_index.command_pos))
+            .cloned();
+        match value {
+            Some(_) => inner.hits += 1,
+            None => inner.misses += 1,
+        }
+        value
+    }
+
+    pub fn stats(&self) -> ValueCacheStats {
+        let inner = self.inner.lock().unwrap();
+        ValueCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            evictions: inner.evictions,
+            entries: inner.values.len() as u64,
+            bytes: inner.size,
+        }
+    }
+
+    pub fn insert(&self, log_index: &LogIndex, value: String) {
+        let size = value.len() as u64 + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old_value) = inner
+            .values
+            .put((log_index.id, log_index.command_pos), value)
+        {
+            inner.size -= old_value.len() as u64 + ENTRY_OVERHEAD;
+        }
+        inner.size += size;
+
+        while inner.size > self.capacity {
+            match inner.values.pop_lru() {
+                Some((_, value)) => {
+                    inner.size -= value.len() as u64 + ENTRY_OVERHEAD;
+                    inner.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Called when the keydir entry of the command is replaced or removed
+    pub fn invalidate(&self, log_index: &LogIndex) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(value) = inner.values.pop(&(log_index.id, log_index.command_pos)) {
+            inner.size -= value.len() as u64 + ENTRY_OVERHEAD;
+        }
+    }
+
+    // Called when the log file is removed
+    pub fn invalidate_file(&self, id: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<(u32, u64)> = inner
+            .values
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(file_id, _)| *file_id == id)
+            .collect();
+
+        for key in keys {
+            if let Some(value) = inner.values.pop(&key) {
+                inner.size -= value.len() as u64 + ENTRY_OVERHEAD;
+            }
+        }
+    }
+}
@@ -1,4 +1,9 @@
+// `failure_derive` expands to impl blocks inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 
 #[derive(Debug, Fail)]
@@ -20,6 +25,16 @@ pub enum CyKvError {
 
     #[fail(display = "key not found:{}", _0)]
     KeyNotFound(String),
+
+    #[fail(display = "invalid argument: {}", _0)]
+    InvalidArgument(String),
+
+    #[fail(display = "the directory {} is locked by another store", _0)]
+    Locked(String),
+
+    // The error sent back by the server
+    #[fail(display = "server error: {}", _0)]
+    Remote(WireError),
 }
 
 impl From<io::Error> for CyKvError {
@@ -47,3 +62,89 @@ impl From<bson::de::Error> for CyKvError {
 }
 
 pub type Result<T> = std::result::Result<T, CyKvError>;
+
+// The stable error codes of the server protocol
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // The key doesn't exist
+    NotFound,
+    // The request can't be parsed or has invalid arguments
+    InvalidRequest,
+    // Reading or writing the files failed
+    Io,
+    // The stored data can't be decoded
+    Corruption,
+    // The store can't be written
+    ReadOnly,
+    // The engine or its cache doesn't support the request
+    Unsupported,
+    // The server can't take the request now, e.g. it serves too many connections
+    Unavailable,
+    Internal,
+    // The write is applied, but syncing it failed, so a crash may lose it
+    Unsynced,
+}
+
+/// `WireError` is the error sent back by the server, derived from `CyKvError`.
+/// A retryable error may succeed if the same request is sent again later.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct WireError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+impl WireError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: false,
+        }
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl From<&CyKvError> for WireError {
+    fn from(err: &CyKvError) -> Self {
+        let code = match err {
+            CyKvError::Remote(err) => return err.clone(),
+            CyKvError::Io(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => ErrorCode::Corruption,
+                io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                    ErrorCode::ReadOnly
+                }
+                io::ErrorKind::Unsupported => ErrorCode::Unsupported,
+                _ => ErrorCode::Io,
+            },
+            // The server turns the requests it can't parse into InvalidArgument,
+            // the JSON errors left come from the files of the store
+            CyKvError::SerdeJson(err) if err.is_io() => ErrorCode::Io,
+            CyKvError::SerdeJson(_) => ErrorCode::Corruption,
+            CyKvError::InvalidArgument(_) => ErrorCode::InvalidRequest,
+            CyKvError::Serialize(_) | CyKvError::Internal => ErrorCode::Internal,
+            CyKvError::Deserialize(_) => ErrorCode::Corruption,
+            CyKvError::KeyNotFound(_) => ErrorCode::NotFound,
+            CyKvError::Locked(_) => ErrorCode::Unavailable,
+        };
+        let retryable = match err {
+            CyKvError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        };
+
+        Self {
+            code,
+            message: err.to_string(),
+            retryable,
+        }
+    }
+}
@@ -1,14 +1,17 @@
-#![feature(linked_list_cursors)]
-
 mod cache;
+mod client;
 mod engine;
 mod error;
+mod protocol;
+mod resp;
 mod server;
 mod utils;
 
 pub use cache::*;
+pub use client::*;
 pub use engine::*;
 pub use error::*;
+pub use protocol::*;
 pub use server::*;
 pub(crate) use utils::*;
 
@@ -0,0 +1,521 @@
+use crate::{CyKvError, ErrorCode, Request, Response, Result, WireError};
+use std::convert::{TryFrom, TryInto};
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+// A client asks for the binary protocol by sending the preamble before its first request,
+// the server agrees by sending the preamble back, a JSON request can't start with it
+pub(crate) const PREAMBLE: &[u8] = b"CYKV\x01";
+// The request id and the opcode, after the length of a frame
+const HEADER_SIZE: usize = 9;
+// The server rejects the larger requests, the connection can't be read any further
+pub(crate) const MAX_REQUEST_SIZE: usize = 64 << 20;
+// The server answers with an error instead of a larger response, e.g. a page of huge values
+pub(crate) const MAX_RESPONSE_SIZE: usize = 256 << 20;
+
+const GET: u8 = 1;
+const SET: u8 = 2;
+const REMOVE: u8 = 3;
+const RANGE: u8 = 4;
+const SCAN_PREFIX: u8 = 5;
+const KEYS: u8 = 6;
+const COUNT: u8 = 7;
+const DELETE_RANGE: u8 = 8;
+const SYNC: u8 = 9;
+const STATS: u8 = 10;
+const RESIZE: u8 = 11;
+
+const RES_OK: u8 = 1;
+const RES_PAGE: u8 = 2;
+const RES_KEYS: u8 = 3;
+const RES_COUNT: u8 = 4;
+const RES_STATS: u8 = 5;
+const RES_ERR: u8 = 6;
+
+// The error codes are sent as their index in the table of the README
+fn error_code_index(code: ErrorCode) -> u8 {
+    match code {
+        ErrorCode::NotFound => 0,
+        ErrorCode::InvalidRequest => 1,
+        ErrorCode::Io => 2,
+        ErrorCode::Corruption => 3,
+        ErrorCode::ReadOnly => 4,
+        ErrorCode::Unsupported => 5,
+        ErrorCode::Unavailable => 6,
+        ErrorCode::Internal => 7,
+        ErrorCode::Unsynced => 8,
+    }
+}
+
+fn error_code(index: u8) -> Option<ErrorCode> {
+    Some(match index {
+        0 => ErrorCode::NotFound,
+        1 => ErrorCode::InvalidRequest,
+        2 => ErrorCode::Io,
+        3 => ErrorCode::Corruption,
+        4 => ErrorCode::ReadOnly,
+        5 => ErrorCode::Unsupported,
+        6 => ErrorCode::Unavailable,
+        7 => ErrorCode::Internal,
+        8 => ErrorCode::Unsynced,
+        _ => return None,
+    })
+}
+
+/// `Protocol` is the encoding of the requests and responses of a connection.
+/// The JSON protocol sends the requests and responses as concatenated JSON values,
+/// the binary protocol sends them in length-prefixed frames tagged with a request id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Json,
+    Binary,
+}
+
+// Read a frame of max_size bytes at most, without its length
+pub(crate) fn read_frame(reader: &mut impl BufRead, max_size: usize) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if !(HEADER_SIZE..=max_size).contains(&len) {
+        return Err(malformed(format!("invalid frame size {}", len)));
+    }
+
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+// Take the next frame out of the buffer if the peer already sent all of it,
+// without waiting for the connection
+pub(crate) fn buffered_frame<R: Read>(
+    reader: &mut BufReader<R>,
+    max_size: usize,
+) -> Option<Vec<u8>> {
+    let buf = reader.buffer();
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+    // The invalid lengths are reported by read_frame
+    if !(HEADER_SIZE..=max_size).contains(&len) || buf.len() < 4 + len {
+        return None;
+    }
+
+    let frame = buf[4..4 + len].to_vec();
+    reader.consume(4 + len);
+    Some(frame)
+}
+
+pub(crate) fn encode_request(id: u64, req: &Request) -> Result<Vec<u8>> {
+    let mut e;
+    match req {
+        Request::Get { Key: key } => {
+            e = Encoder::new(id, GET);
+            e.string(key);
+        }
+        Request::Set {
+            Key: key,
+            Value: value,
+        } => {
+            e = Encoder::new(id, SET);
+            e.string(key);
+            e.string(value);
+        }
+        Request::Remove { Key: key } => {
+            e = Encoder::new(id, REMOVE);
+            e.string(key);
+        }
+        Request::Range {
+            Start: start,
+            End: end,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            e = Encoder::new(id, RANGE);
+            e.bound(start);
+            e.bound(end);
+            e.opt_u64(limit.map(|limit| limit as u64));
+            e.bool(*reverse);
+            e.opt_u64(page_size.map(|size| size as u64));
+        }
+        Request::ScanPrefix {
+            Prefix: prefix,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            e = Encoder::new(id, SCAN_PREFIX);
+            e.string(prefix);
+            e.opt_u64(limit.map(|limit| limit as u64));
+            e.bool(*reverse);
+            e.opt_u64(page_size.map(|size| size as u64));
+        }
+        Request::Keys {
+            Start: start,
+            End: end,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            e = Encoder::new(id, KEYS);
+            e.bound(start);
+            e.bound(end);
+            e.opt_u64(limit.map(|limit| limit as u64));
+            e.bool(*reverse);
+            e.opt_u64(page_size.map(|size| size as u64));
+        }
+        Request::Count {
+            Start: start,
+            End: end,
+        } => {
+            e = Encoder::new(id, COUNT);
+            e.bound(start);
+            e.bound(end);
+        }
+        Request::DeleteRange {
+            Start: start,
+            End: end,
+        } => {
+            e = Encoder::new(id, DELETE_RANGE);
+            e.bound(start);
+            e.bound(end);
+        }
+        Request::Sync => e = Encoder::new(id, SYNC),
+        Request::Stats => e = Encoder::new(id, STATS),
+        Request::Resize { CacheBytes: bytes } => {
+            e = Encoder::new(id, RESIZE);
+            e.u64(*bytes);
+        }
+    }
+    e.finish(MAX_REQUEST_SIZE)
+}
+
+// Decode a frame read by read_frame, the id is returned even if the request is malformed,
+// so the error can be sent back with it
+pub(crate) fn decode_request(frame: &[u8]) -> (u64, Result<Request>) {
+    let mut d = Decoder { buf: frame };
+    let id = d.u64().unwrap_or_default();
+    let req = d.u8().and_then(|opcode| {
+        let req = match opcode {
+            GET => Request::Get { Key: d.string()? },
+            SET => Request::Set {
+                Key: d.string()?,
+                Value: d.string()?,
+            },
+            REMOVE => Request::Remove { Key: d.string()? },
+            RANGE => Request::Range {
+                Start: d.bound()?,
+                End: d.bound()?,
+                Limit: d.opt_usize()?,
+                Reverse: d.bool()?,
+                PageSize: d.opt_usize()?,
+            },
+            SCAN_PREFIX => Request::ScanPrefix {
+                Prefix: d.string()?,
+                Limit: d.opt_usize()?,
+                Reverse: d.bool()?,
+                PageSize: d.opt_usize()?,
+            },
+            KEYS => Request::Keys {
+                Start: d.bound()?,
+                End: d.bound()?,
+                Limit: d.opt_usize()?,
+                Reverse: d.bool()?,
+                PageSize: d.opt_usize()?,
+            },
+            COUNT => Request::Count {
+                Start: d.bound()?,
+                End: d.bound()?,
+            },
+            DELETE_RANGE => Request::DeleteRange {
+                Start: d.bound()?,
+                End: d.bound()?,
+            },
+            SYNC => Request::Sync,
+            STATS => Request::Stats,
+            RESIZE => Request::Resize {
+                CacheBytes: d.u64()?,
+            },
+            _ => return Err(malformed(format!("unknown opcode {}", opcode))),
+        };
+        d.finish()?;
+        Ok(req)
+    });
+    (id, req)
+}
+
+pub(crate) fn encode_response(id: u64, res: &Response) -> Result<Vec<u8>> {
+    let mut e;
+    match res {
+        Response::Ok(value) => {
+            e = Encoder::new(id, RES_OK);
+            e.opt_string(value.as_deref());
+        }
+        Response::Page {
+            Pairs: pairs,
+            More: more,
+        } => {
+            e = Encoder::new(id, RES_PAGE);
+            e.u32(pairs.len() as u32);
+            for (key, value) in pairs {
+                e.string(key);
+                e.string(value);
+            }
+            e.bool(*more);
+        }
+        Response::Keys {
+            Keys: keys,
+            More: more,
+        } => {
+            e = Encoder::new(id, RES_KEYS);
+            e.u32(keys.len() as u32);
+            for key in keys {
+                e.string(key);
+            }
+            e.bool(*more);
+        }
+        Response::Count(count) => {
+            e = Encoder::new(id, RES_COUNT);
+            e.u64(*count);
+        }
+        // The statistics change with the caches, they are sent as JSON
+        Response::Stats(stats) => {
+            e = Encoder::new(id, RES_STATS);
+            e.string(&serde_json::to_string(stats)?);
+        }
+        Response::Err(err) => {
+            e = Encoder::new(id, RES_ERR);
+            e.u8(error_code_index(err.code));
+            e.bool(err.retryable);
+            e.string(&err.message);
+        }
+    }
+    e.finish(MAX_RESPONSE_SIZE)
+}
+
+pub(crate) fn decode_response(frame: &[u8]) -> Result<(u64, Response)> {
+    let mut d = Decoder { buf: frame };
+    let id = d.u64()?;
+    let res = match d.u8()? {
+        RES_OK => Response::Ok(d.opt_string()?),
+        RES_PAGE => {
+            let len = d.len(8)?;
+            let mut pairs = Vec::with_capacity(len);
+            for _ in 0..len {
+                pairs.push((d.string()?, d.string()?));
+            }
+            Response::Page {
+                Pairs: pairs,
+                More: d.bool()?,
+            }
+        }
+        RES_KEYS => {
+            let len = d.len(4)?;
+            let mut keys = Vec::with_capacity(len);
+            for _ in 0..len {
+                keys.push(d.string()?);
+            }
+            Response::Keys {
+                Keys: keys,
+                More: d.bool()?,
+            }
+        }
+        RES_COUNT => Response::Count(d.u64()?),
+        RES_STATS => Response::Stats(serde_json::from_str(&d.string()?)?),
+        RES_ERR => {
+            let code = d.u8()?;
+            let code = error_code(code)
+                .ok_or_else(|| malformed(format!("unknown error code {}", code)))?;
+            let retryable = d.bool()?;
+            Response::Err(WireError {
+                code,
+                retryable,
+                message: d.string()?,
+            })
+        }
+        opcode => return Err(malformed(format!("unknown opcode {}", opcode))),
+    };
+    d.finish()?;
+    Ok((id, res))
+}
+
+fn malformed(message: String) -> CyKvError {
+    CyKvError::InvalidArgument(format!("malformed frame, {}", message))
+}
+
+// The integers are big-endian, a string is its u32 length and its UTF-8 bytes,
+// an option or a bound is a tag byte followed by its value if it has one
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(id: u64, opcode: u8) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[0; 4]); // the length, set by finish
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.push(opcode);
+        Self { buf }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn opt_u64(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u64(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn opt_string(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.string(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn bound(&mut self, bound: &Bound<String>) {
+        match bound {
+            Unbounded => self.u8(0),
+            Included(key) => {
+                self.u8(1);
+                self.string(key);
+            }
+            Excluded(key) => {
+                self.u8(2);
+                self.string(key);
+            }
+        }
+    }
+
+    // Set the length of the frame, which can't be larger than max_size
+    fn finish(mut self, max_size: usize) -> Result<Vec<u8>> {
+        let len = self.buf.len() - 4;
+        if len > max_size {
+            return Err(CyKvError::InvalidArgument(format!(
+                "the frame of {} bytes is larger than {} bytes",
+                len, max_size
+            )));
+        }
+        self.buf[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        Ok(self.buf)
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(malformed("the frame is truncated".to_owned()));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(malformed(format!("invalid bool {}", tag))),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn opt_usize(&mut self) -> Result<Option<usize>> {
+        match self.tag(1)? {
+            0 => Ok(None),
+            _ => {
+                let value = self.u64()?;
+                let value = usize::try_from(value)
+                    .map_err(|_| malformed(format!("{} is too large", value)))?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    // The number of the items of a list, each taking min_size bytes at least,
+    // checked against the rest of the frame before anything is allocated for them
+    fn len(&mut self, min_size: usize) -> Result<usize> {
+        let len = self.u32()? as usize;
+        if len.saturating_mul(min_size) > self.buf.len() {
+            return Err(malformed("the frame is truncated".to_owned()));
+        }
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| malformed(e.to_string()))
+    }
+
+    fn opt_string(&mut self) -> Result<Option<String>> {
+        match self.tag(1)? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?)),
+        }
+    }
+
+    fn bound(&mut self) -> Result<Bound<String>> {
+        match self.tag(2)? {
+            0 => Ok(Unbounded),
+            1 => Ok(Included(self.string()?)),
+            _ => Ok(Excluded(self.string()?)),
+        }
+    }
+
+    fn tag(&mut self, max: u8) -> Result<u8> {
+        match self.u8()? {
+            tag if tag <= max => Ok(tag),
+            tag => Err(malformed(format!("invalid tag {}", tag))),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.buf.len() {
+            0 => Ok(()),
+            len => Err(malformed(format!("{} bytes after the payload", len))),
+        }
+    }
+}
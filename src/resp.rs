@@ -0,0 +1,819 @@
+use crate::{prefix_range, CyKvError, KvEngine, Result, ScanOptions, WriteOp, MAX_REQUEST_SIZE};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// The longest line of a command: an inline command, or the header of an argument
+const MAX_LINE: u64 = 64 * 1024;
+// The arguments of a command, at most
+const MAX_ARGS: usize = 1024 * 1024;
+// The SCAN cursors remembered by a connection, the oldest ones are dropped first
+const MAX_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// The locks the keys are spread over
+const KEY_LOCKS: usize = 64;
+
+// The commands, to tell a wrong number of arguments from an unknown command
+const COMMANDS: &[&str] = &[
+    "ping", "echo", "quit", "hello", "select", "client", "command", "get", "set", "del", "exists",
+    "mget", "mset", "keys", "scan", "dbsize", "info", "ttl", "pttl",
+];
+
+/// `RespState` is shared by the connections of a server: the expiry times of the keys,
+/// kept in memory only, so the keys never expire after a restart, and the locks of the keys. A command holds the locks of its keys
+/// while it checks their expiry times and writes them, so an expiring key isn't removed
+/// after another connection set it again, and the deadlines map is only locked briefly,
+/// never during the reads and writes of the engine.
+pub(crate) struct RespState {
+    deadlines: Mutex<HashMap<String, Instant>>,
+    locks: Vec<Mutex<()>>,
+}
+
+impl Default for RespState {
+    fn default() -> Self {
+        Self {
+            deadlines: Mutex::default(),
+            locks: (0..KEY_LOCKS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+type KeyGuards<'a> = Vec<MutexGuard<'a, ()>>;
+
+impl RespState {
+    // Lock the keys, in the order of their locks, so two commands can't deadlock
+    fn lock_keys<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> KeyGuards<'_> {
+        let mut locks: Vec<usize> = keys
+            .into_iter()
+            .map(|key| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() as usize % KEY_LOCKS
+            })
+            .collect();
+        locks.sort_unstable();
+        locks.dedup();
+        locks
+            .into_iter()
+            .map(|i| self.locks[i].lock().unwrap())
+            .collect()
+    }
+
+    // Lock the keys of a write of the native protocols, which drops their expiry times,
+    // as SET without KEEPTTL does
+    pub(crate) fn lock_writes(&self, keys: &[&str]) -> KeyGuards<'_> {
+        let locked = self.lock_keys(keys.iter().copied());
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if !deadlines.is_empty() {
+            for key in keys {
+                deadlines.remove(*key);
+            }
+        }
+        locked
+    }
+
+    // Remove the key if its deadline passed, true if it expired, the key is locked
+    fn expire<E: KvEngine>(&self, engine: &E, key: &str) -> Result<bool> {
+        {
+            let mut deadlines = self.deadlines.lock().unwrap();
+            match deadlines.get(key) {
+                Some(deadline) if *deadline <= Instant::now() => deadlines.remove(key),
+                _ => return Ok(false),
+            };
+        }
+        match engine.remove(key.to_owned()) {
+            Ok(_) | Err(CyKvError::KeyNotFound(_)) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Remove the key if its deadline passed, before a read of the native protocols
+    pub(crate) fn expire_key<E: KvEngine>(&self, engine: &E, key: &str) -> Result<()> {
+        let _locked = self.lock_keys([key]);
+        self.expire(engine, key).map(|_| ())
+    }
+
+    // Remove the expired keys in the range, before the reads which list or count the keys
+    pub(crate) fn expire_range<E: KvEngine>(
+        &self,
+        engine: &E,
+        range: &impl RangeBounds<String>,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .deadlines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, deadline)| **deadline <= now && range.contains(*key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let _locked = self.lock_keys([key.as_str()]);
+            self.expire(engine, &key)?;
+        }
+        Ok(())
+    }
+
+    fn expire_all<E: KvEngine>(&self, engine: &E) -> Result<()> {
+        self.expire_range(engine, &..)
+    }
+
+    // Lock all the keys for a write of a range, like DeleteRange
+    pub(crate) fn lock_range_writes(
+        &self,
+        range: &(Bound<String>, Bound<String>),
+    ) -> KeyGuards<'_> {
+        let locked = self.locks.iter().map(|l| l.lock().unwrap()).collect();
+        self.deadlines
+            .lock()
+            .unwrap()
+            .retain(|key, _| !range.contains(key));
+        locked
+    }
+}
+
+/// `Cursors` maps the SCAN cursors of a connection to the last key they returned,
+/// each connection has its own, so the scanners don't evict each other's cursors.
+#[derive(Default)]
+struct Cursors {
+    last_keys: HashMap<u64, String>,
+    ids: VecDeque<u64>, // the cursors from the oldest to the newest
+    next: u64,
+}
+
+impl Cursors {
+    fn insert(&mut self, last_key: String) -> u64 {
+        self.next += 1;
+        let cursor = self.next;
+        self.last_keys.insert(cursor, last_key);
+        self.ids.push_back(cursor);
+        if self.ids.len() > MAX_CURSORS {
+            let oldest = self.ids.pop_front().unwrap();
+            self.last_keys.remove(&oldest);
+        }
+        cursor
+    }
+}
+
+/// `Reply` is a RESP reply, written in RESP2 or RESP3.
+pub(crate) enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    // The message can't span lines
+    pub(crate) fn error(message: impl Into<String>) -> Self {
+        Reply::Error(message.into().replace(['\r', '\n'], " "))
+    }
+
+    fn bulk(value: impl Into<Vec<u8>>) -> Self {
+        Reply::Bulk(value.into())
+    }
+
+    fn value(value: Option<String>) -> Self {
+        value.map_or(Reply::Null, Reply::bulk)
+    }
+
+    // RESP2 has no map, nor null, the maps are flattened into arrays,
+    // and the nulls are sent as null bulk strings
+    pub(crate) fn write(&self, out: &mut impl Write, resp3: bool) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(out, "+{}\r\n", s),
+            Reply::Error(message) => write!(out, "-{}\r\n", message),
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(value) => {
+                write!(out, "${}\r\n", value.len())?;
+                out.write_all(value)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Null if resp3 => out.write_all(b"_\r\n"),
+            Reply::Null => out.write_all(b"$-1\r\n"),
+            Reply::Array(items) => {
+                write!(out, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(out, resp3))
+            }
+            Reply::Map(pairs) => {
+                match resp3 {
+                    true => write!(out, "%{}\r\n", pairs.len())?,
+                    false => write!(out, "*{}\r\n", pairs.len() * 2)?,
+                }
+                pairs.iter().try_for_each(|(key, value)| {
+                    key.write(out, resp3)?;
+                    value.write(out, resp3)
+                })
+            }
+        }
+    }
+}
+
+// Read a command: an array of bulk strings, as the clients send them,
+// or an inline command, a line of words as typed in telnet.
+// A malformed command is an InvalidArgument error, the connection can't be read any further
+pub(crate) fn read_command(reader: &mut impl BufRead) -> Result<Vec<Vec<u8>>> {
+    let line = read_line(reader)?;
+    if line.first() != Some(&b'*') {
+        let words = line.split(|b| b.is_ascii_whitespace());
+        return Ok(words
+            .filter(|w| !w.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect());
+    }
+
+    let len = match parse_len(&line[1..])? {
+        Some(len) if len > MAX_ARGS => return Err(protocol_error("invalid multibulk length")),
+        Some(len) => len,
+        None => 0,
+    };
+    let mut args = Vec::with_capacity(len.min(64));
+    for _ in 0..len {
+        let line = read_line(reader)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..line.len().min(1)])
+            )));
+        }
+        let len = match parse_len(&line[1..])? {
+            Some(len) if len <= MAX_REQUEST_SIZE => len,
+            _ => return Err(protocol_error("invalid bulk length")),
+        };
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("expected CRLF after a bulk string"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+// Read a line, without its CRLF or LF
+fn read_line(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return match line.len() as u64 {
+            MAX_LINE => Err(protocol_error("too big inline request")),
+            _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// A length, None for the negative ones of the null arrays
+fn parse_len(digits: &[u8]) -> Result<Option<usize>> {
+    let len: i64 = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    Ok(usize::try_from(len).ok())
+}
+
+fn protocol_error(message: impl Into<String>) -> CyKvError {
+    CyKvError::InvalidArgument(message.into())
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+/// `RespSession` runs the commands of a RESP connection on the engine.
+/// The keys and values are UTF-8 strings, as in the rest of CyKV.
+pub(crate) struct RespSession<'a, E: KvEngine> {
+    engine: &'a E,
+    state: &'a RespState,
+    cursors: Cursors,
+    resp3: bool, // switched by HELLO
+}
+
+impl<'a, E: KvEngine> RespSession<'a, E> {
+    pub(crate) fn new(engine: &'a E, state: &'a RespState) -> Self {
+        Self {
+            engine,
+            state,
+            cursors: Cursors::default(),
+            resp3: false,
+        }
+    }
+
+    pub(crate) fn resp3(&self) -> bool {
+        self.resp3
+    }
+
+    // Run a command, return its reply, and true if the connection should be closed after it
+    pub(crate) fn run(&mut self, args: Vec<Vec<u8>>) -> (Reply, bool) {
+        let args = match args
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+        {
+            Ok(args) => args,
+            Err(_) => {
+                let err = Reply::error("ERR the keys and values of CyKV are UTF-8 strings");
+                return (err, false);
+            }
+        };
+        let name = args[0].to_ascii_lowercase();
+        let (command, args) = (&args[0], &args[1..]);
+
+        let res = match (name.as_str(), args.len()) {
+            ("ping", 0) => Ok(Reply::Simple("PONG")),
+            ("ping", 1) | ("echo", 1) => Ok(Reply::bulk(args[0].as_str())),
+            ("quit", _) => return (Reply::Simple("OK"), true),
+            ("hello", _) => Ok(self.hello(args)),
+            ("select", 1) => Ok(match args[0].as_str() {
+                "0" => Reply::Simple("OK"),
+                _ => Reply::error("ERR DB index is out of range"),
+            }),
+            ("client", n) if n > 0 => Ok(client(args)),
+            ("command", _) => Ok(Reply::Array(Vec::new())),
+            ("get", 1) => self.get(&args[0]).map(Reply::value),
+            ("set", n) if n >= 2 => self.set(args),
+            ("del", n) if n > 0 => self.del(args),
+            ("exists", n) if n > 0 => self.exists(args),
+            ("mget", n) if n > 0 => self.mget(args),
+            ("mset", n) if n > 0 && n % 2 == 0 => self.mset(args),
+            ("keys", 1) => self.keys(&args[0]),
+            ("scan", n) if n > 0 => self.scan(args),
+            ("dbsize", 0) => self.dbsize(),
+            ("info", _) => self.info(args),
+            ("ttl", 1) => self.ttl(&args[0], 1000),
+            ("pttl", 1) => self.ttl(&args[0], 1),
+            (name, _) if COMMANDS.contains(&name) => Ok(wrong_args(name)),
+            _ => Ok(Reply::error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                args_preview(command),
+                args.iter()
+                    .map(|arg| format!("'{}' ", args_preview(arg)))
+                    .collect::<String>()
+            ))),
+        };
+
+        let reply = res.unwrap_or_else(|e| Reply::error(format!("ERR {}", e)));
+        (reply, false)
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME name]],
+    // there are no users nor connection names, AUTH and SETNAME are accepted as they are
+    fn hello(&mut self, args: &[String]) -> Reply {
+        let mut resp3 = self.resp3;
+        let mut args = args.iter();
+        if let Some(version) = args.next() {
+            resp3 = match version.as_str() {
+                "2" => false,
+                "3" => true,
+                _ => return Reply::error("NOPROTO unsupported protocol version"),
+            };
+        }
+        while let Some(option) = args.next() {
+            let skipped = match option.to_ascii_lowercase().as_str() {
+                "auth" => 2,
+                "setname" => 1,
+                _ => return syntax_error(),
+            };
+            if args.by_ref().take(skipped).count() < skipped {
+                return syntax_error();
+            }
+        }
+
+        self.resp3 = resp3;
+        Reply::Map(vec![
+            (Reply::bulk("server"), Reply::bulk("cykv")),
+            (
+                Reply::bulk("version"),
+                Reply::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (
+                Reply::bulk("proto"),
+                Reply::Integer(if resp3 { 3 } else { 2 }),
+            ),
+            (Reply::bulk("mode"), Reply::bulk("standalone")),
+            (Reply::bulk("role"), Reply::bulk("master")),
+            (Reply::bulk("modules"), Reply::Array(Vec::new())),
+        ])
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        {
+            let _locked = self.state.lock_keys([key]);
+            if self.state.expire(self.engine, key)? {
+                return Ok(None);
+            }
+        }
+        self.engine.get(key.to_owned())
+    }
+
+    // SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]
+    fn set(&self, args: &[String]) -> Result<Reply> {
+        let (key, value) = (&args[0], &args[1]);
+        let (mut nx, mut xx, mut get, mut keep_ttl) = (false, false, false, false);
+        let mut ttl = None;
+
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_str() {
+                "NX" if !xx => nx = true,
+                "XX" if !nx => xx = true,
+                "GET" => get = true,
+                "KEEPTTL" if ttl.is_none() => keep_ttl = true,
+                unit @ "EX" | unit @ "PX" if ttl.is_none() && !keep_ttl => {
+                    let amount = match options.next() {
+                        Some(amount) => amount,
+                        None => return Ok(syntax_error()),
+                    };
+                    let amount: u64 = match amount.parse::<i64>() {
+                        Ok(amount) if amount > 0 => amount as u64,
+                        Ok(_) => {
+                            return Ok(Reply::error("ERR invalid expire time in 'set' command"))
+                        }
+                        Err(_) => {
+                            return Ok(Reply::error("ERR value is not an integer or out of range"))
+                        }
+                    };
+                    ttl = Some(match unit {
+                        "EX" => Duration::from_secs(amount),
+                        _ => Duration::from_millis(amount),
+                    });
+                }
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        // The key is locked, so the condition still holds when the value is set
+        let _locked = self.state.lock_keys([key.as_str()]);
+        self.state.expire(self.engine, key)?;
+        if nx || xx {
+            let old_value = self.engine.get(key.clone())?;
+            if old_value.is_some() == nx {
+                return Ok(match get {
+                    true => Reply::value(old_value),
+                    false => Reply::Null,
+                });
+            }
+        }
+
+        let old_value = self.engine.set(key.clone(), value.clone())?;
+        let mut deadlines = self.state.deadlines.lock().unwrap();
+        match ttl {
+            Some(ttl) => {
+                deadlines.insert(key.clone(), Instant::now() + ttl);
+            }
+            None if !keep_ttl => {
+                deadlines.remove(key);
+            }
+            None => {}
+        }
+
+        Ok(match get {
+            true => Reply::value(old_value),
+            false => Reply::Simple("OK"),
+        })
+    }
+
+    // The keys are removed as one batch
+    fn del(&self, keys: &[String]) -> Result<Reply> {
+        let _locked = self.state.lock_keys(keys.iter().map(String::as_str));
+        for key in keys {
+            self.state.expire(self.engine, key)?;
+            self.state.deadlines.lock().unwrap().remove(key);
+        }
+
+        let batch = keys
+            .iter()
+            .map(|key| WriteOp::Remove { key: key.clone() })
+            .collect();
+        let batch = self.engine.write_batch(batch)?;
+        let mut removed = 0;
+        for res in batch.results {
+            match res {
+                Ok(_) => removed += 1,
+                Err(CyKvError::KeyNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        batch.sync?;
+        Ok(Reply::Integer(removed))
+    }
+
+    // A key given several times is counted as many times
+    fn exists(&self, keys: &[String]) -> Result<Reply> {
+        let mut count = 0;
+        for key in keys {
+            let _locked = self.state.lock_keys([key.as_str()]);
+            if !self.state.expire(self.engine, key)? {
+                count += self
+                    .engine
+                    .count((Included(key.clone()), Included(key.clone())))?;
+            }
+        }
+        Ok(Reply::Integer(count as i64))
+    }
+
+    fn mget(&self, keys: &[String]) -> Result<Reply> {
+        let values = keys
+            .iter()
+            .map(|key| self.get(key).map(Reply::value))
+            .collect::<Result<_>>()?;
+        Ok(Reply::Array(values))
+    }
+
+    // The pairs are set as one batch
+    fn mset(&self, args: &[String]) -> Result<Reply> {
+        let batch = args
+            .chunks(2)
+            .map(|pair| WriteOp::Set {
+                key: pair[0].clone(),
+                value: pair[1].clone(),
+            })
+            .collect();
+        let keys: Vec<&str> = args.iter().step_by(2).map(String::as_str).collect();
+        let _locked = self.state.lock_writes(&keys);
+        let batch = self.engine.write_batch(batch)?;
+        for res in batch.results {
+            res?;
+        }
+        batch.sync?;
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn keys(&self, pattern: &str) -> Result<Reply> {
+        self.state.expire_all(self.engine)?;
+
+        let pattern: Vec<char> = pattern.chars().collect();
+        let keys = self
+            .engine
+            .keys(pattern_range(&pattern), ScanOptions::default())?;
+        let keys = keys
+            .into_iter()
+            .filter(|key| glob_match(&pattern, key))
+            .map(Reply::bulk)
+            .collect();
+        Ok(Reply::Array(keys))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count] [TYPE type],
+    // a cursor resumes after the last key it returned, so the keys set or removed
+    // during the scan don't make it skip the others
+    fn scan(&mut self, args: &[String]) -> Result<Reply> {
+        let cursor: u64 = match args[0].parse() {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(Reply::error("ERR invalid cursor")),
+        };
+        let mut pattern = vec!['*'];
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut strings = true;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = match options.next() {
+                Some(value) => value,
+                None => return Ok(syntax_error()),
+            };
+            match option.to_ascii_uppercase().as_str() {
+                "MATCH" => pattern = value.chars().collect(),
+                "COUNT" => match value.parse() {
+                    Ok(value) if value > 0 => count = value,
+                    _ => return Ok(syntax_error()),
+                },
+                // All the values are strings
+                "TYPE" => strings = value.eq_ignore_ascii_case("string"),
+                _ => return Ok(syntax_error()),
+            }
+        }
+
+        self.state.expire_all(self.engine)?;
+        let (mut start, end) = pattern_range(&pattern);
+        if cursor != 0 {
+            let last_key = match self.cursors.last_keys.get(&cursor) {
+                Some(last_key) => last_key,
+                None => return Ok(Reply::error("ERR invalid cursor")),
+            };
+            let before_start = match &start {
+                Included(prefix) => last_key < prefix,
+                _ => false,
+            };
+            if !before_start {
+                start = Excluded(last_key.clone());
+            }
+        }
+
+        let keys = self
+            .engine
+            .keys((start, end), ScanOptions::default().limit(count))?;
+        let cursor = match keys.len() < count {
+            true => 0,
+            false => self.cursors.insert(keys.last().unwrap().clone()),
+        };
+        let keys = keys
+            .into_iter()
+            .filter(|key| strings && glob_match(&pattern, key))
+            .map(Reply::bulk)
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::bulk(cursor.to_string()),
+            Reply::Array(keys),
+        ]))
+    }
+
+    fn dbsize(&self) -> Result<Reply> {
+        self.state.expire_all(self.engine)?;
+        Ok(Reply::Integer(self.engine.count(..)? as i64))
+    }
+
+    // The sections are server, cykv and keyspace, all of them by default
+    fn info(&self, sections: &[String]) -> Result<Reply> {
+        self.state.expire_all(self.engine)?;
+        let expires = self.state.deadlines.lock().unwrap().len();
+        let stats = self.engine.stats()?;
+        let wanted = |section: &str| {
+            sections.is_empty()
+                || sections.iter().any(|wanted| {
+                    let wanted = wanted.to_ascii_lowercase();
+                    wanted == section || wanted == "all" || wanted == "everything"
+                })
+        };
+
+        let mut info = Vec::new();
+        if wanted("server") {
+            info.push(format!(
+                "# Server\r\nredis_version:7.0.0\r\ncykv_version:{}\r\nredis_mode:standalone\r\n",
+                env!("CARGO_PKG_VERSION")
+            ));
+        }
+        if wanted("cykv") {
+            info.push(format!(
+                "# CyKV\r\nactive_log_id:{}\r\nuncompacted_bytes:{}\r\ncache_hits:{}\r\n\
+                 cache_misses:{}\r\ncache_evictions:{}\r\ncache_resident_bytes:{}\r\n\
+                 cache_peak_resident_bytes:{}\r\ncache_capacity_bytes:{}\r\n",
+                stats.active_log_id,
+                stats.uncompacted_bytes,
+                stats.cache.hits,
+                stats.cache.misses,
+                stats.cache.evictions,
+                stats.cache.resident_bytes,
+                stats.cache.peak_resident_bytes,
+                stats.cache.capacity_bytes
+            ));
+        }
+        if wanted("keyspace") {
+            info.push(format!(
+                "# Keyspace\r\ndb0:keys={},expires={},avg_ttl=0\r\n",
+                stats.keys, expires
+            ));
+        }
+        Ok(Reply::bulk(info.join("\r\n")))
+    }
+
+    // The remaining time to live, in units of unit milliseconds,
+    // -2 if the key doesn't exist, -1 if it doesn't expire
+    fn ttl(&self, key: &str, unit: u128) -> Result<Reply> {
+        let _locked = self.state.lock_keys([key]);
+        if self.state.expire(self.engine, key)? {
+            return Ok(Reply::Integer(-2));
+        }
+        if self
+            .engine
+            .count((Included(key.to_owned()), Included(key.to_owned())))?
+            == 0
+        {
+            return Ok(Reply::Integer(-2));
+        }
+
+        Ok(Reply::Integer(
+            match self.state.deadlines.lock().unwrap().get(key) {
+                Some(deadline) => {
+                    let ms = deadline
+                        .saturating_duration_since(Instant::now())
+                        .as_millis();
+                    ((ms + unit / 2) / unit) as i64
+                }
+                None => -1,
+            },
+        ))
+    }
+}
+
+// CLIENT SETNAME and SETINFO are accepted, the connections have no names
+fn client(args: &[String]) -> Reply {
+    match args[0].to_ascii_lowercase().as_str() {
+        "setname" | "setinfo" => Reply::Simple("OK"),
+        "getname" => Reply::Null,
+        _ => Reply::error(format!(
+            "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+            args_preview(&args[0])
+        )),
+    }
+}
+
+// The start of an argument quoted in an error
+fn args_preview(arg: &str) -> String {
+    arg.chars().take(128).collect()
+}
+
+// The range of the keys a pattern can match, the keys starting with its literal prefix
+fn pattern_range(pattern: &[char]) -> (Bound<String>, Bound<String>) {
+    let prefix: String = pattern
+        .iter()
+        .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+        .collect();
+    match prefix.is_empty() {
+        true => (Unbounded, Unbounded),
+        false => prefix_range(prefix),
+    }
+}
+
+// Match a glob pattern like Redis: * matches any string, ? any char,
+// [abc], [^abc] and [a-z] a char of the class, and \ escapes the next char
+fn glob_match(pattern: &[char], text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // After a mismatch, the last * takes one more char
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(next) = match_char(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// The position after the element of the pattern at p, if it matches the char
+fn match_char(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match *pattern.get(p)? {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        '[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            // An unterminated class ends with the pattern
+            while let Some(&x) = pattern.get(i) {
+                match x {
+                    ']' => break,
+                    '\\' if i + 1 < pattern.len() => {
+                        matched |= pattern[i + 1] == c;
+                        i += 2;
+                    }
+                    lo if pattern.get(i + 1) == Some(&'-')
+                        && pattern.get(i + 2).is_some_and(|&hi| hi != ']') =>
+                    {
+                        let hi = pattern[i + 2];
+                        let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                        matched |= lo <= c && c <= hi;
+                        i += 3;
+                    }
+                    x => {
+                        matched |= x == c;
+                        i += 1;
+                    }
+                }
+            }
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        x => (x == c).then_some(p + 1),
+    }
+}
@@ -1,82 +1,1189 @@
-use crate::{CyKvError, KvEngine, Result};
+use crate::resp::{read_command, Reply, RespSession, RespState};
+use crate::{
+    buffered_frame, decode_request, encode_response, prefix_range, read_frame, BatchResults,
+    CyKvError, ErrorCode, KvEngine, Protocol, Result, ScanOptions, StoreStats, WireError, WriteOp,
+    MAX_REQUEST_SIZE, PREAMBLE,
+};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::thread;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+// The pairs of a scan are sent back in pages of this many pairs by default
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 100_000;
+// The bytes of the requests read from a connection at once
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+// The consecutive writes of a connection applied as one batch at most
+const MAX_BATCH: usize = 1024;
+// How long the server reads the rest of a malformed request before closing the connection
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+// How often the refused connections being closed are read
+const LINGER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// The refused connections waiting to be answered, more are reset
+const MAX_REFUSING: usize = 1024;
+// How long the server waits before accepting again when the system runs out of resources
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// How often the server checks if the connections finished, when shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub const DEFAULT_WORKERS: usize = 64;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    // The threads serving the connections, each serves one connection at a time
+    pub workers: usize,
+    // The connections served or waiting for a worker,
+    // the others are refused with an Unavailable error
+    pub max_connections: usize,
+    // Close a connection which sends no request for this long, None waits forever
+    pub idle_timeout: Option<Duration>,
+    // The time to read the rest of a request once it starts, and to write a response,
+    // None waits forever
+    pub read_timeout: Option<Duration>,
+    // How long a connection waits for a free worker before it's refused
+    // with an Unavailable error, None waits forever
+    pub queue_timeout: Option<Duration>,
+    // How long a shutdown waits for the requests in flight,
+    // the connections still open are closed after it
+    pub shutdown_timeout: Duration,
+    // Also listen for the Redis clients on this address, None doesn't
+    pub resp_addr: Option<SocketAddr>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_WORKERS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            queue_timeout: Some(DEFAULT_QUEUE_TIMEOUT),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            resp_addr: None,
+        }
+    }
+}
+
+/// `Server` serves the connections with a fixed pool of workers,
+/// the accepted connections wait in a queue for a free worker, for `queue_timeout` at most,
+/// and the connections beyond `max_connections` are refused.
+/// A worker is freed when its client closes the connection, or stays idle too long.
+/// The connections of the RESP listener, if any, share the workers and the limit.
 pub struct Server<E: KvEngine> {
     engine: E,
     listener: TcpListener,
+    resp_listener: Option<TcpListener>,
+    resp: Arc<RespState>, // the expiry times of the keys set by the RESP connections
+    options: ServerOptions,
+    connections: Arc<AtomicUsize>, // the connections served or queued
+    served: Arc<Mutex<HashMap<usize, TcpStream>>>, // map worker index to its connection
+    shutdown: ShutdownHandle,
+}
+
+/// `ShutdownHandle` stops a running server, from another thread,
+/// like the one handling the signals. The server stops accepting connections,
+/// lets the requests in flight finish, syncs the engine, and `run()` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    addrs: Vec<SocketAddr>, // connected to wake the threads waiting in accept
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            for addr in &self.addrs {
+                let _ = TcpStream::connect_timeout(addr, LINGER_TIMEOUT);
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
 }
 
 impl<E: KvEngine> Server<E> {
     pub fn new(engine: E, addr: SocketAddr) -> Result<Self> {
+        Server::with_options(engine, addr, ServerOptions::default())
+    }
+
+    pub fn with_options(engine: E, addr: SocketAddr, options: ServerOptions) -> Result<Self> {
+        if options.workers == 0 || options.max_connections == 0 {
+            return Err(CyKvError::InvalidArgument(
+                "the server needs a worker and a connection at least".to_owned(),
+            ));
+        }
+
         let listener = TcpListener::bind(addr)?;
-        Ok(Self { engine, listener })
+        let resp_listener = match options.resp_addr {
+            Some(addr) => Some(TcpListener::bind(addr)?),
+            None => None,
+        };
+        let addrs = std::iter::once(&listener)
+            .chain(&resp_listener)
+            .map(wake_addr)
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            engine,
+            listener,
+            resp_listener,
+            resp: Arc::default(),
+            options,
+            connections: Arc::default(),
+            served: Arc::default(),
+            shutdown: ShutdownHandle {
+                requested: Arc::default(),
+                addrs,
+            },
+        })
+    }
+
+    // The bound address, useful when the port was chosen by the system
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // The bound address of the RESP listener, None if there is none
+    pub fn local_resp_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.resp_listener {
+            Some(listener) => Ok(Some(listener.local_addr()?)),
+            None => Ok(None),
+        }
     }
 
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Serve the connections until the shutdown
     pub fn run(&self) -> Result<()> {
-        for stream in self.listener.incoming() {
-            let engine = self.engine.clone();
-            thread::spawn(move || {
-                if let Err(e) = serve(engine, stream.unwrap()) {
-                    eprintln!("{:?}", e);
+        let queue = Arc::new(ConnectionQueue::default());
+        let refuser = spawn_refuser();
+        if let Some(queue_timeout) = self.options.queue_timeout {
+            spawn_reaper(
+                Arc::clone(&queue),
+                queue_timeout,
+                refuser.clone(),
+                Arc::clone(&self.connections),
+            );
+        }
+        let workers: Vec<JoinHandle<()>> = (0..self.options.workers)
+            .map(|index| self.spawn_worker(index, Arc::clone(&queue)))
+            .collect();
+
+        // Each listener is served by its own thread
+        let listeners = std::iter::once((&self.listener, Frontend::Native))
+            .chain(self.resp_listener.iter().map(|l| (l, Frontend::Resp)));
+        let (shutdown, connections) = (&self.shutdown, &*self.connections);
+        let max_connections = self.options.max_connections;
+        thread::scope(|scope| {
+            for (listener, frontend) in listeners {
+                let (queue, refuser) = (&*queue, refuser.clone());
+                scope.spawn(move || {
+                    accept_loop(
+                        listener,
+                        frontend,
+                        shutdown,
+                        connections,
+                        max_connections,
+                        queue,
+                        &refuser,
+                    )
+                });
+            }
+        });
+
+        // The workers exit when the queue is closed and empty
+        queue.close();
+        drop(refuser);
+        self.stop(workers)
+    }
+
+    // Let the requests in flight finish, and sync the engine
+    fn stop(&self, workers: Vec<JoinHandle<()>>) -> Result<()> {
+        info!(
+            "shutting down, {} connections are open",
+            self.connections.load(Ordering::SeqCst)
+        );
+        // A connection ends after the requests already received,
+        // the queued connections are closed without being served
+        for stream in self.served.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        let deadline = Instant::now() + self.options.shutdown_timeout;
+        while self.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        match self.connections.load(Ordering::SeqCst) {
+            0 => {
+                for worker in workers {
+                    let _ = worker.join();
                 }
-            });
+            }
+            open => {
+                warn!("closing {} connections still open", open);
+                for stream in self.served.lock().unwrap().values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
         }
 
+        // Waits for a write, or a compaction, still running
+        self.engine.sync()?;
+        info!("shut down");
         Ok(())
     }
+
+    fn spawn_worker(&self, index: usize, queue: Arc<ConnectionQueue>) -> JoinHandle<()> {
+        let engine = self.engine.clone();
+        let resp = Arc::clone(&self.resp);
+        let options = self.options.clone();
+        let connections = Arc::clone(&self.connections);
+        let served = Arc::clone(&self.served);
+        let shutdown = self.shutdown.clone();
+
+        thread::spawn(move || loop {
+            let (stream, frontend) = match queue.pop() {
+                Some(queued) => queued,
+                None => break,
+            };
+            // Registered before checking the shutdown, so stop() sees the connection,
+            // or the worker sees the shutdown
+            if let Ok(clone) = stream.try_clone() {
+                served.lock().unwrap().insert(index, clone);
+            }
+
+            if !shutdown.is_shutdown() {
+                // A panic is contained in its connection, and the worker goes on
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    serve(engine.clone(), stream, frontend, &options, &resp)
+                }));
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("{}", e),
+                    Err(_) => error!("serving a connection panicked"),
+                }
+            }
+            served.lock().unwrap().remove(&index);
+            connections.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+}
+
+/// The protocols of a listener: the JSON and the binary ones, or the Redis one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frontend {
+    Native,
+    Resp,
 }
 
-fn serve<E: KvEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let reader = serde_json::Deserializer::from_reader(&stream);
+// The address connected to wake the thread accepting on the listener
+fn wake_addr(listener: &TcpListener) -> Result<SocketAddr> {
+    let mut addr = listener.local_addr()?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(addr)
+}
 
-    for req in reader.into_iter::<Request>() {
-        if let Err(e) = req {
-            // Client close the connection
-            if e.line() == 0 && e.column() == 0 {
-                return Ok(());
+// Accept the connections of a listener until the shutdown,
+// and queue them for the workers, or refuse them over max_connections
+fn accept_loop(
+    listener: &TcpListener,
+    frontend: Frontend,
+    shutdown: &ShutdownHandle,
+    connections: &AtomicUsize,
+    max_connections: usize,
+    queue: &ConnectionQueue,
+    refuser: &SyncSender<(TcpStream, Frontend)>,
+) {
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // The client gave up before the connection was accepted
+            Err(e) if is_transient(&e) => continue,
+            // Like running out of file descriptors, wait for some connections to close
+            Err(e) => {
+                warn!("accepting a connection failed: {}", e);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "refused a connection, {} connections are open",
+                max_connections
+            );
+            // The connection is reset if too many are being refused
+            let _ = refuser.try_send((stream, frontend));
+            continue;
+        }
+        queue.push(stream, frontend);
+    }
+}
+
+/// `ConnectionQueue` holds the accepted connections until a worker is free,
+/// in the order they were accepted.
+#[derive(Default)]
+struct ConnectionQueue {
+    waiting: Mutex<Waiting>,
+    ready: Condvar,   // notified for the workers when a connection is queued
+    changed: Condvar, // notified for the reaper when the queue changes
+}
+
+#[derive(Default)]
+struct Waiting {
+    connections: VecDeque<(TcpStream, Frontend, Instant)>, // with the time it was queued
+    closed: bool,
+}
+
+impl ConnectionQueue {
+    fn push(&self, stream: TcpStream, frontend: Frontend) {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting
+            .connections
+            .push_back((stream, frontend, Instant::now()));
+        self.ready.notify_one();
+        self.changed.notify_all();
+    }
+
+    // Wait for a connection, None once the queue is closed and empty
+    fn pop(&self) -> Option<(TcpStream, Frontend)> {
+        let mut waiting = self.waiting.lock().unwrap();
+        loop {
+            if let Some((stream, frontend, _)) = waiting.connections.pop_front() {
+                self.changed.notify_all();
+                return Some((stream, frontend));
+            }
+            if waiting.closed {
+                return None;
             }
-            let res = Response::Err("error when read request".to_owned());
-            serde_json::to_writer(&stream, &res)?;
-            return Err(CyKvError::SerdeJson(e));
+            waiting = self.ready.wait(waiting).unwrap();
         }
+    }
 
-        let res = match req.unwrap() {
-            Request::Get { Key: key } => match engine.get(key) {
-                Ok(value) => Response::Ok(value),
-                Err(e) => Response::Err("".to_owned()),
-            },
-            Request::Set {
-                Key: key,
-                Value: value,
-            } => match engine.set(key, value) {
-                Ok(_) => Response::Ok(None),
-                Err(e) => Response::Err("".to_owned()),
-            },
-            Request::Remove { Key: key } => match engine.remove(key) {
-                Ok(_) => Response::Ok(None),
-                Err(e) => Response::Err("".to_owned()),
-            },
-        };
+    fn close(&self) {
+        self.waiting.lock().unwrap().closed = true;
+        self.ready.notify_all();
+        self.changed.notify_all();
+    }
+}
+
+// The reaper refuses the connections queued longer than queue_timeout,
+// as the workers may all be serving idle connections
+fn spawn_reaper(
+    queue: Arc<ConnectionQueue>,
+    queue_timeout: Duration,
+    refuser: SyncSender<(TcpStream, Frontend)>,
+    connections: Arc<AtomicUsize>,
+) {
+    thread::spawn(move || {
+        let mut waiting = queue.waiting.lock().unwrap();
+        while !waiting.closed {
+            let deadline = match waiting.connections.front() {
+                Some((_, _, queued)) => *queued + queue_timeout,
+                None => {
+                    waiting = queue.changed.wait(waiting).unwrap();
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            if now < deadline {
+                waiting = queue
+                    .changed
+                    .wait_timeout(waiting, deadline - now)
+                    .unwrap()
+                    .0;
+                continue;
+            }
+
+            let (stream, frontend, _) = waiting.connections.pop_front().unwrap();
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "refused a connection which waited {:?} for a worker",
+                queue_timeout
+            );
+            let _ = refuser.try_send((stream, frontend));
+        }
+    });
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+// The refuser answers the first request of the connections over the limit
+// with a retryable error, and closes them when the client does, or after a while.
+// One thread serves all the refused connections, with non-blocking reads
+fn spawn_refuser() -> SyncSender<(TcpStream, Frontend)> {
+    let (sender, receiver) = mpsc::sync_channel::<(TcpStream, Frontend)>(MAX_REFUSING);
+    thread::spawn(move || {
+        let mut lingering: Vec<(TcpStream, Instant)> = Vec::new();
+        loop {
+            let received = match lingering.is_empty() {
+                true => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                false => receiver.recv_timeout(LINGER_POLL_INTERVAL),
+            };
+            match received {
+                Ok((stream, frontend)) => {
+                    if refuse(&stream, frontend).is_ok() {
+                        lingering.push((stream, Instant::now() + LINGER_TIMEOUT));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) if lingering.is_empty() => break,
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(LINGER_POLL_INTERVAL),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            let now = Instant::now();
+            lingering.retain(|(stream, deadline)| now < *deadline && !drain(stream));
+        }
+    });
+    sender
+}
 
-        serde_json::to_writer(&stream, &res)?;
+fn refuse(mut stream: &TcpStream, frontend: Frontend) -> Result<()> {
+    stream.set_write_timeout(Some(LINGER_POLL_INTERVAL))?;
+    match frontend {
+        Frontend::Native => {
+            let err = WireError {
+                retryable: true,
+                ..WireError::new(ErrorCode::Unavailable, "too many connections")
+            };
+            serde_json::to_writer(stream, &Response::Err(err))?;
+        }
+        Frontend::Resp => {
+            Reply::error("ERR max number of clients reached").write(&mut stream, false)?
+        }
     }
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_nonblocking(true)?;
+    Ok(())
+}
 
+// Read what a refused connection has sent, true if the client closed it
+fn drain(mut stream: &TcpStream) -> bool {
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+// Closing with a request unread would reset the connection,
+// and the client could lose the response written before
+fn linger(stream: &TcpStream) -> Result<()> {
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(LINGER_TIMEOUT))?;
+    let _ = io::copy(&mut stream.take(1 << 20), &mut io::sink());
     Ok(())
 }
 
+/// `RequestReader` reads a connection with the idle timeout until a request starts,
+/// and with the read timeout for the rest of the request.
+/// The responses are buffered while the client sends more requests,
+/// and flushed before the reader waits for the connection.
+struct RequestReader<'a, 's> {
+    stream: &'s TcpStream,
+    writer: &'a RefCell<BufWriter<&'s TcpStream>>,
+    idle: &'a Cell<bool>, // no byte of the next request was read yet
+    options: &'a ServerOptions,
+    timeout: Option<Option<Duration>>, // the timeout set on the stream
+}
+
+impl Read for RequestReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.writer.borrow_mut().flush()?;
+
+        let timeout = match self.idle.get() {
+            true => self.options.idle_timeout,
+            false => self.options.read_timeout,
+        };
+        if self.timeout != Some(timeout) {
+            self.stream.set_read_timeout(timeout)?;
+            self.timeout = Some(timeout);
+        }
+
+        let len = self.stream.read(buf)?;
+        if len > 0 {
+            self.idle.set(false);
+        }
+        Ok(len)
+    }
+}
+
+type Requests<'a, 's> = BufReader<RequestReader<'a, 's>>;
+
+fn serve<E: KvEngine>(
+    engine: E,
+    stream: TcpStream,
+    frontend: Frontend,
+    options: &ServerOptions,
+    resp: &RespState,
+) -> Result<()> {
+    stream.set_write_timeout(options.read_timeout)?;
+    // The responses are coalesced by the writer already
+    stream.set_nodelay(true)?;
+    let writer = RefCell::new(BufWriter::new(&stream));
+    let idle = Cell::new(true);
+    let mut reader = BufReader::with_capacity(
+        READ_BUFFER_SIZE,
+        RequestReader {
+            stream: &stream,
+            writer: &writer,
+            idle: &idle,
+            options,
+            timeout: None,
+        },
+    );
+
+    // The first bytes choose the protocol of the connection
+    let res = match wait_request(&mut reader, &idle, frontend == Frontend::Native)? {
+        Some(_) if frontend == Frontend::Resp => {
+            serve_resp(&engine, &stream, reader, &writer, resp)
+        }
+        Some(first) if first == PREAMBLE[0] => {
+            let responder = Responder {
+                writer: &writer,
+                protocol: Protocol::Binary,
+            };
+            serve_binary(&engine, &stream, reader, &responder, resp)
+        }
+        Some(_) => {
+            let responder = Responder {
+                writer: &writer,
+                protocol: Protocol::Json,
+            };
+            serve_json(&engine, &stream, reader, &responder, resp)
+        }
+        None => Ok(()),
+    };
+    // The responses to the last requests before the client closed the connection
+    let flushed = writer.borrow_mut().flush();
+    res?;
+    Ok(flushed?)
+}
+
+// Wait for the first byte of the next request, without consuming it,
+// None if the client closed the connection, or left it idle too long
+fn wait_request(
+    reader: &mut Requests,
+    idle: &Cell<bool>,
+    skip_whitespace: bool,
+) -> Result<Option<u8>> {
+    loop {
+        idle.set(true);
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) => {
+                debug!("closed an idle connection: {}", e);
+                return Ok(None);
+            }
+        };
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        // The JSON values may be separated by whitespace
+        let spaces = match skip_whitespace {
+            true => buf.iter().take_while(|b| b.is_ascii_whitespace()).count(),
+            false => 0,
+        };
+        if spaces < buf.len() {
+            let first = buf[spaces];
+            reader.consume(spaces);
+            idle.set(false);
+            return Ok(Some(first));
+        }
+        reader.consume(spaces);
+    }
+}
+
+// The JSON requests are run one by one, in the order they are received
+fn serve_json<E: KvEngine>(
+    engine: &E,
+    stream: &TcpStream,
+    mut reader: Requests,
+    responder: &Responder,
+    resp: &RespState,
+) -> Result<()> {
+    let idle = reader.get_ref().idle;
+
+    loop {
+        let req = Request::deserialize(&mut serde_json::Deserializer::from_reader(&mut reader));
+        match req {
+            Ok(req) => execute(engine, resp, 0, req, responder)?,
+            // The client went away, or was too slow, in the middle of a request
+            Err(e) if e.is_io() || e.is_eof() => return Err(e.into()),
+            Err(e) => {
+                let err = CyKvError::InvalidArgument(format!("invalid request, {}", e));
+                responder.send(0, &Response::Err((&err).into()))?;
+                responder.flush()?;
+                linger(stream)?;
+                return Err(err);
+            }
+        }
+
+        if wait_request(&mut reader, idle, true)?.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+// The binary requests are run in the order they are received too,
+// but the consecutive writes already received are applied as one batch
+fn serve_binary<E: KvEngine>(
+    engine: &E,
+    stream: &TcpStream,
+    mut reader: Requests,
+    responder: &Responder,
+    resp: &RespState,
+) -> Result<()> {
+    let idle = reader.get_ref().idle;
+    let mut preamble = [0; PREAMBLE.len()];
+    reader.read_exact(&mut preamble)?;
+    if preamble[..4] != PREAMBLE[..4] {
+        // Not a binary client, the error is sent as JSON
+        let err = WireError::new(ErrorCode::InvalidRequest, "invalid request");
+        let responder = Responder {
+            protocol: Protocol::Json,
+            ..*responder
+        };
+        responder.send(0, &Response::Err(err))?;
+        responder.flush()?;
+        linger(stream)?;
+        return Err(CyKvError::InvalidArgument("invalid preamble".to_owned()));
+    }
+    // The client reads the version the server speaks from the answer,
+    // and the server can't read a newer version
+    responder.writer.borrow_mut().write_all(PREAMBLE)?;
+    if preamble != PREAMBLE {
+        responder.flush()?;
+        return Err(CyKvError::InvalidArgument(format!(
+            "unsupported protocol version {}",
+            preamble[4]
+        )));
+    }
+
+    // A request read while batching the writes before it
+    let mut next = None;
+    loop {
+        let (id, req) = match next.take() {
+            Some(next) => next,
+            None => {
+                if wait_request(&mut reader, idle, false)?.is_none() {
+                    return Ok(());
+                }
+                match read_frame(&mut reader, MAX_REQUEST_SIZE) {
+                    Ok(frame) => decode_request(&frame),
+                    // The rest of the connection can't be read without a valid length
+                    Err(e @ CyKvError::InvalidArgument(_)) => {
+                        responder.send(0, &Response::Err(WireError::from(&e)))?;
+                        responder.flush()?;
+                        linger(stream)?;
+                        return Err(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        let req = match req {
+            Ok(req) => req,
+            // The next frame can still be read
+            Err(e) => {
+                responder.send(id, &Response::Err(WireError::from(&e)))?;
+                continue;
+            }
+        };
+        let op = match write_op(req) {
+            Ok(op) => op,
+            Err(req) => {
+                execute(engine, resp, id, req, responder)?;
+                continue;
+            }
+        };
+
+        let mut ids = vec![id];
+        let mut batch = vec![op];
+        while batch.len() < MAX_BATCH {
+            let frame = match buffered_frame(&mut reader, MAX_REQUEST_SIZE) {
+                Some(frame) => frame,
+                None => break,
+            };
+            match decode_request(&frame) {
+                (id, Ok(req)) => match write_op(req) {
+                    Ok(op) => {
+                        ids.push(id);
+                        batch.push(op);
+                    }
+                    Err(req) => {
+                        next = Some((id, Ok(req)));
+                        break;
+                    }
+                },
+                (id, Err(e)) => {
+                    next = Some((id, Err(e)));
+                    break;
+                }
+            }
+        }
+
+        let keys: Vec<&str> = batch
+            .iter()
+            .map(|op| match op {
+                WriteOp::Set { key, .. } | WriteOp::Remove { key } => key.as_str(),
+            })
+            .collect();
+        let locked = resp.lock_writes(&keys);
+        let results = engine.write_batch(batch);
+        drop(locked);
+        match results {
+            // The writes applied before a failed sync can't be undone,
+            // they are answered with Unsynced, so the clients don't apply them again
+            Ok(BatchResults { results, sync }) => {
+                let unsynced = sync.err().map(|e| {
+                    WireError::new(
+                        ErrorCode::Unsynced,
+                        format!("applied, but the sync failed: {}", e),
+                    )
+                });
+                for (id, res) in ids.into_iter().zip(results) {
+                    let res = match (res, &unsynced) {
+                        (Ok(old_value), None) => Response::Ok(old_value),
+                        (Ok(_), Some(err)) => Response::Err(err.clone()),
+                        (Err(e), _) => Response::Err(WireError::from(&e)),
+                    };
+                    responder.send(id, &res)?;
+                }
+            }
+            Err(e) => {
+                let err = WireError::from(&e);
+                for id in ids {
+                    responder.send(id, &Response::Err(err.clone()))?;
+                }
+            }
+        }
+    }
+}
+
+// The Redis commands are run one by one, the replies are buffered like the responses
+fn serve_resp<E: KvEngine>(
+    engine: &E,
+    stream: &TcpStream,
+    mut reader: Requests,
+    writer: &RefCell<BufWriter<&TcpStream>>,
+    resp: &RespState,
+) -> Result<()> {
+    let idle = reader.get_ref().idle;
+    let mut session = RespSession::new(engine, resp);
+
+    loop {
+        match read_command(&mut reader) {
+            Ok(args) if args.is_empty() => {}
+            Ok(args) => {
+                let (reply, quit) = session.run(args);
+                reply.write(&mut *writer.borrow_mut(), session.resp3())?;
+                if quit {
+                    return Ok(());
+                }
+            }
+            // The rest of the connection can't be read
+            Err(CyKvError::InvalidArgument(message)) => {
+                let reply = Reply::error(format!("ERR Protocol error: {}", message));
+                reply.write(&mut *writer.borrow_mut(), session.resp3())?;
+                writer.borrow_mut().flush()?;
+                linger(stream)?;
+                return Err(CyKvError::InvalidArgument(message));
+            }
+            Err(e) => return Err(e),
+        }
+
+        if wait_request(&mut reader, idle, false)?.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+// Set and Remove are batched, the other requests are returned back
+fn write_op(req: Request) -> std::result::Result<WriteOp, Request> {
+    match req {
+        Request::Set {
+            Key: key,
+            Value: value,
+        } => Ok(WriteOp::Set { key, value }),
+        Request::Remove { Key: key } => Ok(WriteOp::Remove { key }),
+        req => Err(req),
+    }
+}
+
+/// `Responder` writes the responses in the protocol of the connection,
+/// the id of the request is only sent by the binary protocol.
+struct Responder<'a, 's> {
+    writer: &'a RefCell<BufWriter<&'s TcpStream>>,
+    protocol: Protocol,
+}
+
+impl Responder<'_, '_> {
+    fn send(&self, id: u64, res: &Response) -> Result<()> {
+        self.try_send(id, res).map(drop)
+    }
+
+    // Send the response, false if it's too large for a frame,
+    // the client gets the error instead
+    fn try_send(&self, id: u64, res: &Response) -> Result<bool> {
+        let mut writer = self.writer.borrow_mut();
+        match self.protocol {
+            Protocol::Json => serde_json::to_writer(&mut *writer, res)?,
+            Protocol::Binary => match encode_response(id, res) {
+                Ok(frame) => writer.write_all(&frame)?,
+                Err(e) => {
+                    writer.write_all(&encode_response(id, &Response::Err(WireError::from(&e)))?)?;
+                    return Ok(false);
+                }
+            },
+        }
+        Ok(true)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.writer.borrow_mut().flush()?)
+    }
+}
+
+// Run a request and send back its responses, the reads remove the expired keys
+// the Redis clients set first, and the writes drop the expiry times of their keys
+fn execute<E: KvEngine>(
+    engine: &E,
+    resp: &RespState,
+    id: u64,
+    req: Request,
+    responder: &Responder,
+) -> Result<()> {
+    // The reads don't see the keys the Redis clients set to expire
+    let expired = match &req {
+        Request::Get { Key: key } => resp.expire_key(engine, key),
+        Request::Range {
+            Start: start,
+            End: end,
+            ..
+        }
+        | Request::Keys {
+            Start: start,
+            End: end,
+            ..
+        }
+        | Request::Count {
+            Start: start,
+            End: end,
+        } => resp.expire_range(engine, &(start.clone(), end.clone())),
+        Request::ScanPrefix { Prefix: prefix, .. } => {
+            resp.expire_range(engine, &prefix_range(prefix.clone()))
+        }
+        _ => Ok(()),
+    };
+    if let Err(e) = expired {
+        return responder.send(id, &Response::Err(WireError::from(&e)));
+    }
+
+    let res: Result<Response> = match req {
+        Request::Get { Key: key } => engine.get(key).map(Response::Ok),
+        Request::Set {
+            Key: key,
+            Value: value,
+        } => {
+            let _locked = resp.lock_writes(&[&key]);
+            engine.set(key, value).map(Response::Ok)
+        }
+        Request::Remove { Key: key } => {
+            let _locked = resp.lock_writes(&[&key]);
+            engine
+                .remove(key)
+                .map(|old_value| Response::Ok(Some(old_value)))
+        }
+        Request::Range {
+            Start: start,
+            End: end,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            let pairs = engine.range((start, end), ScanOptions { reverse, limit });
+            return write_pages(responder, id, pairs, page_size, pair_page);
+        }
+        Request::ScanPrefix {
+            Prefix: prefix,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            let pairs = engine.scan_prefix(prefix, ScanOptions { reverse, limit });
+            return write_pages(responder, id, pairs, page_size, pair_page);
+        }
+        Request::Keys {
+            Start: start,
+            End: end,
+            Limit: limit,
+            Reverse: reverse,
+            PageSize: page_size,
+        } => {
+            let keys = KeyBatches {
+                engine,
+                start,
+                end,
+                reverse,
+                remaining: limit.unwrap_or(usize::MAX),
+                batch_size: clamp_page_size(page_size),
+                batch: Vec::new().into_iter(),
+                done: false,
+            };
+            return write_pages(responder, id, keys, page_size, key_page);
+        }
+        Request::Count {
+            Start: start,
+            End: end,
+        } => engine.count((start, end)).map(Response::Count),
+        Request::DeleteRange {
+            Start: start,
+            End: end,
+        } => {
+            let range = (start, end);
+            let _locked = resp.lock_range_writes(&range);
+            engine.delete_range(range).map(Response::Count)
+        }
+        Request::Sync => engine.sync().map(|()| Response::Ok(None)),
+        Request::Resize { CacheBytes: bytes } => {
+            engine.resize_cache(bytes).map(|()| Response::Ok(None))
+        }
+        Request::Stats => engine.stats().map(Response::Stats),
+    };
+    let res = res.unwrap_or_else(|e| Response::Err(WireError::from(&e)));
+
+    responder.send(id, &res)
+}
+
+fn clamp_page_size(page_size: Option<usize>) -> usize {
+    page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+}
+
+fn pair_page(pairs: Vec<(String, String)>, more: bool) -> Response {
+    Response::Page {
+        Pairs: pairs,
+        More: more,
+    }
+}
+
+fn key_page(keys: Vec<String>, more: bool) -> Response {
+    Response::Keys {
+        Keys: keys,
+        More: more,
+    }
+}
+
+// Write the items of a scan in pages, read from the engine one page at a time,
+// the last page has More set to false, an error ends the pages with Err,
+// a full page is sent once the next item is read, so only an empty scan has an empty page
+fn write_pages<T>(
+    responder: &Responder,
+    id: u64,
+    items: impl Iterator<Item = Result<T>>,
+    page_size: Option<usize>,
+    page_of: impl Fn(Vec<T>, bool) -> Response,
+) -> Result<()> {
+    let page_size = clamp_page_size(page_size);
+    let mut items = items.peekable();
+    let mut page = Vec::with_capacity(page_size);
+
+    while let Some(item) = items.next() {
+        match item {
+            Ok(item) => page.push(item),
+            Err(e) => return responder.send(id, &Response::Err(WireError::from(&e))),
+        }
+
+        if page.len() == page_size && items.peek().is_some() {
+            // An error instead of the page ends the pages
+            if !responder.try_send(id, &page_of(mem::take(&mut page), true))? {
+                return Ok(());
+            }
+        }
+    }
+
+    responder.send(id, &page_of(page, false))
+}
+
+// The keys of a range, read from the engine a batch at a time,
+// so the server never holds all of them
+struct KeyBatches<'a, E> {
+    engine: &'a E,
+    start: Bound<String>, // moved past the keys read, or end if reverse
+    end: Bound<String>,
+    reverse: bool,
+    remaining: usize, // the limit left
+    batch_size: usize,
+    batch: std::vec::IntoIter<String>,
+    done: bool, // the last batch is read
+}
+
+impl<E: KvEngine> Iterator for KeyBatches<'_, E> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(key) = self.batch.next() {
+            return Some(Ok(key));
+        }
+        if self.done || self.remaining == 0 {
+            return None;
+        }
+
+        let limit = self.batch_size.min(self.remaining);
+        let options = ScanOptions {
+            reverse: self.reverse,
+            limit: Some(limit),
+        };
+        let keys = match self
+            .engine
+            .keys((self.start.clone(), self.end.clone()), options)
+        {
+            Ok(keys) => keys,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        self.done = keys.len() < limit;
+        self.remaining -= keys.len();
+        if let Some(last) = keys.last() {
+            match self.reverse {
+                true => self.end = Bound::Excluded(last.clone()),
+                false => self.start = Bound::Excluded(last.clone()),
+            }
+        }
+        self.batch = keys.into_iter();
+        self.batch.next().map(Ok)
+    }
+}
+
+// Get returns the value, Set returns the previous value,
+// Remove returns the removed value
+// Range and ScanPrefix return the pairs in pages, of PageSize pairs at most,
+// until a page with More set to false, Keys returns the keys in pages the same way
+// Count returns the count
+// DeleteRange returns the number of the removed keys
+// Sync persists the data buffered by the cache
+// Stats returns the statistics of the store and its caches
+// Resize changes the memory budget of the cache
+// bounds are encoded as {"Included":"key"}, {"Excluded":"key"} or "Unbounded"
 #[derive(Serialize, Deserialize, Debug)]
 // #[serde(tag = "type")]
+#[allow(non_snake_case)]
 pub enum Request {
-    Get { Key: String },
-    Set { Key: String, Value: String },
-    Remove { Key: String },
+    Get {
+        Key: String,
+    },
+    Set {
+        Key: String,
+        Value: String,
+    },
+    Remove {
+        Key: String,
+    },
+    Range {
+        Start: Bound<String>,
+        End: Bound<String>,
+        #[serde(default)]
+        Limit: Option<usize>,
+        #[serde(default)]
+        Reverse: bool,
+        #[serde(default)]
+        PageSize: Option<usize>,
+    },
+    ScanPrefix {
+        Prefix: String,
+        #[serde(default)]
+        Limit: Option<usize>,
+        #[serde(default)]
+        Reverse: bool,
+        #[serde(default)]
+        PageSize: Option<usize>,
+    },
+    Keys {
+        Start: Bound<String>,
+        End: Bound<String>,
+        #[serde(default)]
+        Limit: Option<usize>,
+        #[serde(default)]
+        Reverse: bool,
+        #[serde(default)]
+        PageSize: Option<usize>,
+    },
+    Count {
+        Start: Bound<String>,
+        End: Bound<String>,
+    },
+    DeleteRange {
+        Start: Bound<String>,
+        End: Bound<String>,
+    },
+    Sync,
+    Stats,
+    Resize {
+        CacheBytes: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 // #[serde(tag = "type")]
+#[allow(non_snake_case)]
 pub enum Response {
     Ok(Option<String>),
-    Err(String),
+    Page {
+        Pairs: Vec<(String, String)>,
+        More: bool,
+    },
+    Keys {
+        Keys: Vec<String>,
+        More: bool,
+    },
+    Count(u64),
+    Stats(StoreStats),
+    Err(WireError),
 }
@@ -1,9 +1,120 @@
+use crate::*;
+use std::fs::File;
+use std::io;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
 use std::path::{Path, PathBuf};
 
 pub(crate) fn log_path(dir: &Path, id: u32) -> PathBuf {
     dir.join(format!("{}.log", id))
 }
 
+pub(crate) fn index_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{}.index", id))
+}
+
+// Ids of all the log files in dir, in ascending order
+pub(crate) fn log_ids(dir: &Path) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        if let Some(ext) = entry.path().extension() {
+            if ext == "log" {
+                let id = entry
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .trim_end_matches(".log")
+                    .parse()
+                    .map_err(|_| CyKvError::Internal)?;
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+
+    Ok(ids)
+}
+
+// BTreeMap::range() panics with these bounds, and they contain no keys anyway
+pub(crate) fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Unbounded, _) | (_, Unbounded) => false,
+        (Included(start), Included(end)) => start > end,
+        (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end)) => start >= end,
+    }
+}
+
+// The range of all the keys starting with the prefix
+pub(crate) fn prefix_range(prefix: String) -> (Bound<String>, Bound<String>) {
+    // The smallest string greater than all the keys with the prefix:
+    // increase the last char which has a successor, and drop the chars after it
+    let mut end = prefix.clone();
+    while let Some(c) = end.pop() {
+        let next = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return (Included(prefix), Excluded(end));
+        }
+    }
+
+    (Included(prefix), Unbounded)
+}
+
+// Positioned reads and writes, which don't use the cursor of the file,
+// so the concurrent readers can share a handle.
+// On Windows they move the cursor, which none of the callers rely on
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, pos)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, pos)
+}
+
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match read_at(file, buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                buf = &mut buf[len..];
+                pos += len as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match write_at(file, buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                buf = &buf[len..];
+                pos += len as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
 // pub fn for_each_log(dir: &Path, handle: fn() ) -> Result<u32> {
 // 	for entry in dir.read_dir()? {
 // 		let entry = entry?;
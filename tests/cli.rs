@@ -0,0 +1,100 @@
+use cykv::*;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use tempfile::TempDir;
+
+fn start_server() -> Result<(CyStore, SocketAddr)> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(NoCacheManager))?;
+    let server = Server::new(store.clone(), "127.0.0.1:0".parse().unwrap())?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.run());
+    Ok((store, addr))
+}
+
+fn cli(addr: SocketAddr, args: &[&str], stdin: &str) -> Result<Output> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cykv-cli"))
+        .arg("--addr")
+        .arg(addr.to_string())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(stdin.as_bytes())?;
+    Ok(child.wait_with_output()?)
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn cli_one_shot() -> Result<()> {
+    let (store, addr) = start_server()?;
+
+    let output = cli(addr, &["set", "key1", "two words"], "")?;
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "(nil)\n");
+    assert_eq!(store.get("key1".to_owned())?, Some("two words".to_owned()));
+
+    let output = cli(addr, &["get", "key1"], "")?;
+    assert_eq!(stdout(&output), "\"two words\"\n");
+
+    let output = cli(addr, &["--json", "get", "key2"], "")?;
+    assert_eq!(stdout(&output), "null\n");
+
+    // The failed command sets the exit status
+    let output = cli(addr, &["rm", "key2"], "")?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("key not found"));
+
+    let output = cli(addr, &["--json", "rm", "key2"], "")?;
+    assert!(!output.status.success());
+    assert!(stdout(&output).starts_with(r#"{"Err":{"Code":"NotFound""#));
+
+    Ok(())
+}
+
+#[test]
+fn cli_batch() -> Result<()> {
+    let (_store, addr) = start_server()?;
+    let commands = r#"
+# load some keys
+set a 1
+set b "quoted \" value"
+set c 3
+set --flag 4
+scan - c
+scan --reverse --limit 2
+keys b
+count
+bogus
+delrange a c
+get c
+"#;
+
+    let output = cli(addr, &["--json"], commands)?;
+    // The failed command doesn't stop the batch, but sets the exit status
+    assert!(!output.status.success());
+    let lines: Vec<&str> = stdout(&output).lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "null",
+            "null",
+            "null",
+            "null",
+            r#"[["--flag","4"],["a","1"],["b","quoted \" value"]]"#,
+            r#"[["c","3"],["b","quoted \" value"]]"#,
+            r#"["b","c"]"#,
+            "4",
+            r#"{"Err":{"Code":"InvalidRequest","Message":"invalid argument: invalid command bogus, try help","Retryable":false}}"#,
+            "2",
+            r#""3""#,
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,399 @@
+use cykv::*;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server() -> Result<(CyStore, SocketAddr)> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(NoCacheManager))?;
+    let server = Server::new(store.clone(), "127.0.0.1:0".parse().unwrap())?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.run());
+    Ok((store, addr))
+}
+
+#[test]
+fn client_as_engine() -> Result<()> {
+    let (store, addr) = start_server()?;
+    let client = Client::connect(addr)?;
+
+    assert_eq!(client.set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        client.set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    assert_eq!(client.remove("key1".to_owned())?, "value2");
+    match client.remove("key1".to_owned()) {
+        Err(CyKvError::KeyNotFound(_)) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    for i in 0..2500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // The scan spans several pages of the server
+    let pairs = client
+        .range(.., ScanOptions::default())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 2500);
+    assert_eq!(pairs[2499], ("key2499".to_owned(), "value2499".to_owned()));
+
+    let keys = client.keys(
+        (
+            Included("key0010".to_owned()),
+            Excluded("key0020".to_owned()),
+        ),
+        ScanOptions {
+            reverse: true,
+            limit: Some(3),
+        },
+    )?;
+    assert_eq!(keys, vec!["key0019", "key0018", "key0017"]);
+    let keys = client.keys(.., ScanOptions::default().reverse().limit(2000))?;
+    assert_eq!(keys.len(), 2000);
+    assert_eq!(
+        (keys[0].as_str(), keys[1999].as_str()),
+        ("key2499", "key0500")
+    );
+    assert_eq!(
+        client.scan("key0100".to_owned(), "key0102".to_owned())?,
+        vec!["value100", "value101", "value102"]
+    );
+    assert_eq!(
+        client
+            .scan_prefix("key01".to_owned(), ScanOptions::default())
+            .count(),
+        100
+    );
+
+    // A scan dropped before its last page doesn't disturb the later requests
+    let mut pairs = client.range(.., ScanOptions::default());
+    assert!(pairs.next().is_some());
+    drop(pairs);
+    assert_eq!(
+        client.count((Included("key1000".to_owned()), Unbounded))?,
+        1500
+    );
+
+    assert_eq!(client.delete_range(.."key1000".to_owned())?, 1000);
+    assert_eq!(client.count(..)?, 1500);
+    client.sync()?;
+    assert_eq!(client.stats()?.keys, 1500);
+
+    // NoCacheManager has no memory budget to resize
+    match client.resize_cache(1 << 20) {
+        Err(CyKvError::Remote(err)) => assert_eq!(err.code, ErrorCode::Unsupported),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_protocols() -> Result<()> {
+    let (_store, addr) = start_server()?;
+    let value = "quotes \", newlines \n, NUL \0 and ünicode";
+
+    for &protocol in &[Protocol::Json, Protocol::Binary] {
+        let options = ClientOptions {
+            protocol,
+            ..ClientOptions::default()
+        };
+        let client = Client::with_options(addr, options)?;
+        let key = format!("{:?}", protocol);
+
+        client.set(key.clone(), value.to_owned())?;
+        assert_eq!(client.get(key.clone())?, Some(value.to_owned()));
+        assert_eq!(
+            client.set(key.clone(), String::new())?,
+            Some(value.to_owned())
+        );
+        assert_eq!(client.get(key.clone())?, Some(String::new()));
+        assert_eq!(client.get("missing".to_owned())?, None);
+        match client.remove("missing".to_owned()) {
+            Err(CyKvError::KeyNotFound(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        for i in 0..1500 {
+            client.set(format!("{}{:04}", key, i), value.to_owned())?;
+        }
+        let pairs = client
+            .scan_prefix(key.clone(), ScanOptions::default().reverse())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 1501);
+        assert_eq!(pairs[0], (format!("{}1499", key), value.to_owned()));
+        let keys = client.keys(key.clone()..format!("{}~", key), ScanOptions::default())?;
+        assert_eq!(keys.len(), 1501);
+        assert_eq!(client.delete_range(key.clone()..format!("{}1", key))?, 1001);
+        assert_eq!(client.stats()?.keys, client.count(..)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_pipeline() -> Result<()> {
+    let (store, addr) = start_server()?;
+
+    for &protocol in &[Protocol::Json, Protocol::Binary] {
+        let options = ClientOptions {
+            protocol,
+            ..ClientOptions::default()
+        };
+        let client = Client::with_options(addr, options)?;
+        let key = format!("{:?}", protocol);
+
+        let mut pipeline = client.pipeline();
+        pipeline
+            .set(key.clone(), "value1".to_owned())
+            .get(key.clone())
+            .set(key.clone(), "value2".to_owned())
+            .remove("missing".to_owned())
+            .remove(key.clone())
+            .get(key.clone());
+        assert_eq!(pipeline.len(), 6);
+        let results = pipeline.execute()?;
+        assert!(pipeline.is_empty());
+
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[0].as_ref().unwrap(), &None);
+        assert_eq!(results[1].as_ref().unwrap(), &Some("value1".to_owned()));
+        assert_eq!(results[2].as_ref().unwrap(), &Some("value1".to_owned()));
+        assert!(matches!(results[3], Err(CyKvError::KeyNotFound(_))));
+        assert_eq!(results[4].as_ref().unwrap(), &Some("value2".to_owned()));
+        assert_eq!(results[5].as_ref().unwrap(), &None);
+
+        // A pipeline larger than the window is sent in several rounds
+        let value = "v".repeat(1000);
+        let batch = (0..2000)
+            .map(|i| WriteOp::Set {
+                key: format!("{}{:04}", key, i),
+                value: value.clone(),
+            })
+            .collect();
+        let results = client.write_batch(batch)?.results;
+        assert!(results.iter().all(|res| matches!(res, Ok(None))));
+        assert_eq!(
+            store.get(format!("{}1999", key))?.map(|value| value.len()),
+            Some(1000)
+        );
+
+        // The connection is still in sync for the next requests
+        assert_eq!(client.count(key.clone()..format!("{}~", key))?, 2000);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_concurrent_requests() -> Result<()> {
+    let (_store, addr) = start_server()?;
+    let client = Client::connect(addr)?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let client = client.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), i.to_string())?;
+                    assert_eq!(client.get(key)?, Some(i.to_string()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(client.count(..)?, 100);
+
+    Ok(())
+}
+
+#[test]
+fn client_request_timeout() -> Result<()> {
+    // The connections are accepted by the system but never answered
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let options = ClientOptions {
+        request_timeout: Some(Duration::from_millis(200)),
+        ..ClientOptions::default()
+    };
+    let client = Client::with_options(listener.local_addr()?, options)?;
+
+    match client.get("key".to_owned()) {
+        Err(CyKvError::Io(_)) | Err(CyKvError::SerdeJson(_)) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_reconnects() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        // The pooled connection is closed, as by a restarted server
+        drop(listener.accept()?);
+
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+        (&stream).write_all(br#"{"Ok":"value"}"#)?;
+        Ok(())
+    });
+
+    // The fake server speaks JSON
+    let options = ClientOptions {
+        protocol: Protocol::Json,
+        ..ClientOptions::default()
+    };
+    let client = Client::with_options(addr, options)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn client_drops_stale_pool() -> Result<()> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(NoCacheManager))?;
+    let options = ServerOptions {
+        workers: 16,
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..ServerOptions::default()
+    };
+    let server = Server::with_options(store.clone(), "127.0.0.1:0".parse().unwrap(), options)?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.run());
+    for i in 0..1500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    // Fill the pool with the connections of unfinished scans
+    let options = ClientOptions {
+        retries: 0,
+        ..ClientOptions::default()
+    };
+    let client = Client::with_options(addr, options)?;
+    let mut scans: Vec<_> = (0..8)
+        .map(|_| client.range(.., ScanOptions::default()))
+        .collect();
+    for scan in &mut scans {
+        assert_eq!(scan.count(), 1500);
+    }
+
+    // The server closes them all, the requests still go through
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.set("key".to_owned(), "value".to_owned())?, None);
+    assert_eq!(client.remove("key".to_owned())?, "value");
+
+    Ok(())
+}
+
+#[test]
+fn client_doesnt_resend_writes() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        // The connection breaks after the request is received
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+        drop(stream);
+
+        // A read is sent again over a new connection
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+        (&stream).write_all(br#"{"Ok":"value"}"#)?;
+        Ok(())
+    });
+
+    let options = ClientOptions {
+        protocol: Protocol::Json,
+        retries: 0,
+        ..ClientOptions::default()
+    };
+    let client = Client::with_options(addr, options.clone())?;
+    // The broken pooled connection doesn't use up the retries
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    server.join().unwrap()?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Set { .. }))));
+        drop(stream);
+
+        // The write isn't sent again, the next connection gets the next request
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+        (&stream).write_all(br#"{"Ok":null}"#)?;
+        Ok(())
+    });
+    // Not even with retries left
+    let options = ClientOptions {
+        retries: 2,
+        ..options
+    };
+    let client = Client::with_options(addr, options.clone())?;
+    assert!(client.set("key".to_owned(), "value".to_owned()).is_err());
+    assert_eq!(client.get("key".to_owned())?, None);
+    server.join().unwrap()?;
+
+    // Nor are the writes of a pipeline
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Set { .. }))));
+        assert!(matches!(requests.next(), Some(Ok(Request::Remove { .. }))));
+        drop(stream);
+
+        let (stream, _) = listener.accept()?;
+        let mut requests = serde_json::Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(requests.next(), Some(Ok(Request::Get { .. }))));
+        (&stream).write_all(br#"{"Ok":null}"#)?;
+        Ok(())
+    });
+    let client = Client::with_options(addr, options)?;
+    let mut pipeline = client.pipeline();
+    pipeline
+        .set("key".to_owned(), "value".to_owned())
+        .remove("other".to_owned());
+    assert!(pipeline.execute().is_err());
+    assert_eq!(client.get("key".to_owned())?, None);
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+#[test]
+fn client_oversized_request() -> Result<()> {
+    let (store, addr) = start_server()?;
+    let client = Client::connect(addr)?;
+
+    // Refused before it's sent, the connection goes on
+    match client.set("key".to_owned(), "x".repeat(64 << 20)) {
+        Err(CyKvError::InvalidArgument(message)) => assert!(message.contains("larger than")),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(client.set("key".to_owned(), "value".to_owned())?, None);
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use cykv::*;
+
+// Look the key up as the chunk cache does, and insert it on a miss,
+// return whether it's cached afterwards
+fn access(policy: &mut dyn EvictionPolicy<String, u32>, key: &str) -> bool {
+    if policy.get(&key.to_owned()).is_some() {
+        return true;
+    }
+    match policy.put(key.to_owned(), 0) {
+        Some((evicted, _)) => evicted != key,
+        None => true,
+    }
+}
+
+#[test]
+fn clock_second_chance() {
+    let mut policy = ClockPolicy::new(3);
+    for key in ["a", "b", "c"] {
+        assert_eq!(policy.put(key.to_owned(), 0), None);
+    }
+
+    // The hand clears the bit of a and evicts the next entry
+    assert!(policy.get(&"a".to_owned()).is_some());
+    assert_eq!(policy.victim(), Some(&"b".to_owned()));
+    assert_eq!(policy.put("d".to_owned(), 0), Some(("b".to_owned(), 0)));
+    assert_eq!(policy.put("e".to_owned(), 0), Some(("c".to_owned(), 0)));
+    // a isn't referenced again, its second chance is used up
+    assert_eq!(policy.put("f".to_owned(), 0), Some(("a".to_owned(), 0)));
+
+    // With all the bits set, the hand sweeps the whole ring once
+    for key in ["d", "e", "f"] {
+        assert!(policy.get(&key.to_owned()).is_some());
+    }
+    assert_eq!(policy.put("g".to_owned(), 0), Some(("d".to_owned(), 0)));
+    assert_eq!(policy.len(), 3);
+}
+
+#[test]
+fn segmented_lru_promotion() {
+    // 8 protected entries of 10
+    let mut policy = SegmentedLruPolicy::new(10);
+    for i in 0..10 {
+        assert_eq!(policy.put(format!("k{}", i), i), None);
+    }
+
+    // A hit promotes the entry, the probation segment is evicted first
+    assert!(policy.get(&"k0".to_owned()).is_some());
+    assert_eq!(policy.victim(), Some(&"k1".to_owned()));
+    for i in 0..20 {
+        policy.put(format!("scan{}", i), i);
+    }
+    assert!(policy.contains(&"k0".to_owned()));
+    assert!(!policy.contains(&"k1".to_owned()));
+
+    // The protected segment overflows to the probation segment,
+    // the demoted entry is evicted after the older probation entries
+    for i in 11..19 {
+        assert!(policy.get(&format!("scan{}", i)).is_some());
+    }
+    assert_eq!(policy.victim(), Some(&"scan19".to_owned()));
+    assert_eq!(
+        policy.put("x".to_owned(), 0),
+        Some(("scan19".to_owned(), 19))
+    );
+    assert_eq!(policy.put("y".to_owned(), 0), Some(("k0".to_owned(), 0)));
+    assert_eq!(policy.len(), 10);
+}
+
+#[test]
+fn tiny_lfu_rejects_scan() {
+    let mut policy = TinyLfuPolicy::new(10);
+    let hot: Vec<String> = (0..5).map(|i| format!("hot{}", i)).collect();
+    for _ in 0..3 {
+        for key in &hot {
+            access(&mut policy, key);
+        }
+    }
+
+    // A long scan of keys accessed once, among the accesses to the hot keys
+    let mut admitted = 0;
+    for i in 0..1000 {
+        assert!(access(&mut policy, &hot[i % hot.len()]));
+        if access(&mut policy, &format!("scan{}", i)) {
+            admitted += 1;
+        }
+    }
+    for key in &hot {
+        assert!(policy.contains(key), "{} evicted", key);
+    }
+    assert!(admitted < 100, "{} scanned keys admitted", admitted);
+
+    // LRU keeps the scanned keys instead
+    let mut policy = LruPolicy::new(10);
+    for i in 0..1000 {
+        access(&mut policy, &hot[i % hot.len()]);
+        access(&mut policy, &format!("scan{}", i));
+    }
+    assert!(policy.contains(&"scan999".to_owned()));
+}
@@ -0,0 +1,328 @@
+use cykv::*;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Return the RESP address, then the native one
+fn start_server() -> Result<(CyStore, SocketAddr, SocketAddr, ShutdownHandle)> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(NoCacheManager))?;
+    let options = ServerOptions {
+        resp_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..ServerOptions::default()
+    };
+    let server = Server::with_options(store.clone(), "127.0.0.1:0".parse().unwrap(), options)?;
+    let addr = server.local_resp_addr()?.unwrap();
+    let native_addr = server.local_addr()?;
+    let handle = server.shutdown_handle();
+    thread::spawn(move || server.run());
+    Ok((store, addr, native_addr, handle))
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Int(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+use Value::*;
+
+fn bulk(value: &str) -> Value {
+    Bulk(value.to_owned())
+}
+
+fn ok() -> Value {
+    Simple("OK".to_owned())
+}
+
+/// `RespConnection` sends the commands as arrays of bulk strings, like the Redis clients.
+struct RespConnection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl RespConnection {
+    fn new(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self { stream, reader })
+    }
+
+    fn send(&mut self, args: &[&str]) -> Result<()> {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.stream.write_all(command.as_bytes())?;
+        Ok(())
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<Value> {
+        self.send(args)?;
+        self.read()
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        let len = || rest.parse::<i64>().unwrap();
+        Ok(match kind {
+            "+" => Simple(rest.to_owned()),
+            "-" => Error(rest.to_owned()),
+            ":" => Int(len()),
+            "_" => Null,
+            "$" if len() < 0 => Null,
+            "$" => {
+                let mut value = vec![0; len() as usize + 2];
+                self.reader.read_exact(&mut value)?;
+                value.truncate(len() as usize);
+                Bulk(String::from_utf8(value).unwrap())
+            }
+            "*" => Array((0..len()).map(|_| self.read()).collect::<Result<_>>()?),
+            "%" => Map((0..len())
+                .map(|_| Ok((self.read()?, self.read()?)))
+                .collect::<Result<_>>()?),
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+}
+
+#[test]
+fn resp_strings() -> Result<()> {
+    let (store, addr, _, _) = start_server()?;
+    let mut conn = RespConnection::new(addr)?;
+
+    assert_eq!(conn.call(&["PING"])?, Simple("PONG".to_owned()));
+    assert_eq!(conn.call(&["echo", "hello"])?, bulk("hello"));
+    assert_eq!(conn.call(&["SET", "key", "value"])?, ok());
+    assert_eq!(conn.call(&["GET", "key"])?, bulk("value"));
+    assert_eq!(conn.call(&["GET", "missing"])?, Null);
+    // The keys are shared with the other protocols
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    assert_eq!(conn.call(&["SET", "key", "other", "NX"])?, Null);
+    assert_eq!(conn.call(&["SET", "new", "value", "XX"])?, Null);
+    assert_eq!(conn.call(&["SET", "new", "value", "NX"])?, ok());
+    assert_eq!(
+        conn.call(&["SET", "key", "other", "XX", "GET"])?,
+        bulk("value")
+    );
+    assert_eq!(conn.call(&["GET", "key"])?, bulk("other"));
+
+    assert_eq!(conn.call(&["MSET", "a", "1", "b", "2", "c", "3"])?, ok());
+    assert_eq!(
+        conn.call(&["MGET", "a", "missing", "c"])?,
+        Array(vec![bulk("1"), Null, bulk("3")])
+    );
+    assert_eq!(conn.call(&["EXISTS", "a", "a", "missing"])?, Int(2));
+    assert_eq!(conn.call(&["DEL", "a", "b", "missing"])?, Int(2));
+    assert_eq!(conn.call(&["EXISTS", "a"])?, Int(0));
+    assert_eq!(conn.call(&["DBSIZE"])?, Int(3));
+
+    // The errors don't close the connection
+    assert_eq!(
+        conn.call(&["GET"])?,
+        Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+    assert_eq!(
+        conn.call(&["SET", "key", "value", "EX", "0"])?,
+        Error("ERR invalid expire time in 'set' command".to_owned())
+    );
+    assert_eq!(
+        conn.call(&["SET", "key", "value", "NX", "XX"])?,
+        Error("ERR syntax error".to_owned())
+    );
+    match conn.call(&["HSET", "hash", "field", "value"])? {
+        Error(message) => assert!(message.starts_with("ERR unknown command 'HSET'")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    // The inline commands, as typed in telnet
+    conn.stream
+        .write_all(b"SET inline  value\r\nGET inline\n")?;
+    assert_eq!(conn.read()?, ok());
+    assert_eq!(conn.read()?, bulk("value"));
+
+    // The commands can be pipelined
+    conn.send(&["SET", "x", "1"])?;
+    conn.send(&["GET", "x"])?;
+    conn.send(&["QUIT"])?;
+    assert_eq!(conn.read()?, ok());
+    assert_eq!(conn.read()?, bulk("1"));
+    assert_eq!(conn.read()?, ok());
+    assert_eq!(conn.reader.read(&mut [0; 16])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn resp_expiry() -> Result<()> {
+    let (_store, addr, native_addr, _) = start_server()?;
+    let mut conn = RespConnection::new(addr)?;
+
+    assert_eq!(conn.call(&["SET", "short", "value", "PX", "100"])?, ok());
+    assert_eq!(conn.call(&["SET", "long", "value", "EX", "100"])?, ok());
+    assert_eq!(conn.call(&["SET", "kept", "value", "PX", "100"])?, ok());
+    assert_eq!(conn.call(&["SET", "kept", "other", "KEEPTTL"])?, ok());
+    assert_eq!(conn.call(&["SET", "persistent", "value"])?, ok());
+    assert_eq!(conn.call(&["SET", "hidden:1", "value", "PX", "100"])?, ok());
+    assert_eq!(conn.call(&["SET", "hidden:2", "value", "PX", "100"])?, ok());
+    assert_eq!(conn.call(&["TTL", "long"])?, Int(100));
+    assert_eq!(conn.call(&["TTL", "persistent"])?, Int(-1));
+    assert_eq!(conn.call(&["TTL", "missing"])?, Int(-2));
+    match conn.call(&["PTTL", "short"])? {
+        Int(ms) => assert!(0 < ms && ms <= 100, "{}", ms),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    thread::sleep(Duration::from_millis(150));
+    // The native reads don't see the expired keys either
+    let client = Client::connect(native_addr)?;
+    assert_eq!(client.get("hidden:1".to_owned())?, None);
+    assert_eq!(client.count("hidden:".to_owned().."hidden;".to_owned())?, 0);
+    assert_eq!(conn.call(&["GET", "short"])?, Null);
+    assert_eq!(conn.call(&["EXISTS", "kept"])?, Int(0));
+    assert_eq!(conn.call(&["GET", "long"])?, bulk("value"));
+    assert_eq!(conn.call(&["DBSIZE"])?, Int(2));
+
+    // Setting a key again clears its expiry time
+    assert_eq!(conn.call(&["SET", "long", "value"])?, ok());
+    assert_eq!(conn.call(&["TTL", "long"])?, Int(-1));
+
+    // So does a write of the native protocol
+    assert_eq!(conn.call(&["SET", "native", "value", "PX", "100"])?, ok());
+    client.set("native".to_owned(), "other".to_owned())?;
+    assert_eq!(conn.call(&["TTL", "native"])?, Int(-1));
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(conn.call(&["GET", "native"])?, bulk("other"));
+
+    Ok(())
+}
+
+#[test]
+fn resp_keys_and_scan() -> Result<()> {
+    let (store, addr, _, _) = start_server()?;
+    for i in 0..25 {
+        store.set(format!("user:{:02}", i), "value".to_owned())?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    let mut conn = RespConnection::new(addr)?;
+
+    assert_eq!(
+        conn.call(&["KEYS", "user:1[0-2]"])?,
+        Array(vec![bulk("user:10"), bulk("user:11"), bulk("user:12")])
+    );
+    assert_eq!(conn.call(&["KEYS", "*her"])?, Array(vec![bulk("other")]));
+    assert_eq!(conn.call(&["KEYS", "user:?"])?, Array(vec![]));
+
+    // The cursor resumes after the keys already returned
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    let mut calls = 0;
+    loop {
+        let reply = conn.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "10"])?;
+        calls += 1;
+        match reply {
+            Array(mut reply) => {
+                let page = reply.pop().unwrap();
+                cursor = match reply.pop().unwrap() {
+                    Bulk(cursor) => cursor,
+                    reply => panic!("unexpected cursor {:?}", reply),
+                };
+                match page {
+                    Array(page) => keys.extend(page),
+                    reply => panic!("unexpected page {:?}", reply),
+                }
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(calls, 3);
+    assert_eq!(keys.len(), 25);
+    assert_eq!(keys[24], bulk("user:24"));
+
+    // Another connection can't evict the cursors of this one
+    let reply = conn.call(&["SCAN", "0", "COUNT", "10"])?;
+    let mut other = RespConnection::new(addr)?;
+    for _ in 0..1100 {
+        other.call(&["SCAN", "0", "COUNT", "1"])?;
+    }
+    let cursor = match reply {
+        Array(reply) => match &reply[0] {
+            Bulk(cursor) => cursor.clone(),
+            reply => panic!("unexpected cursor {:?}", reply),
+        },
+        reply => panic!("unexpected reply {:?}", reply),
+    };
+    match conn.call(&["SCAN", &cursor, "COUNT", "10"])? {
+        Array(reply) => assert_ne!(reply[0], bulk("0")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    assert_eq!(
+        conn.call(&["SCAN", "12345"])?,
+        Error("ERR invalid cursor".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn resp_hello_and_info() -> Result<()> {
+    let (_store, addr, _, handle) = start_server()?;
+    let mut conn = RespConnection::new(addr)?;
+    assert_eq!(conn.call(&["SET", "key", "value", "EX", "60"])?, ok());
+
+    // RESP2 flattens the map of HELLO
+    match conn.call(&["HELLO", "2"])? {
+        Array(fields) => assert_eq!(fields[..2], [bulk("server"), bulk("cykv")]),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match conn.call(&["HELLO", "3", "SETNAME", "test"])? {
+        Map(fields) => assert!(fields.contains(&(bulk("proto"), Int(3)))),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    assert_eq!(conn.call(&["GET", "missing"])?, Null);
+    assert_eq!(
+        conn.call(&["HELLO", "4"])?,
+        Error("NOPROTO unsupported protocol version".to_owned())
+    );
+
+    match conn.call(&["INFO", "keyspace"])? {
+        Bulk(info) => {
+            assert!(info.contains("db0:keys=1,expires=1"), "{}", info);
+            assert!(!info.contains("# Server"));
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match conn.call(&["INFO"])? {
+        Bulk(info) => assert!(info.contains("# Server") && info.contains("active_log_id:")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    // A malformed command closes the connection after the error
+    conn.stream.write_all(b"*1\r\n+PING\r\n")?;
+    assert_eq!(
+        conn.read()?,
+        Error("ERR Protocol error: expected '$', got '+'".to_owned())
+    );
+    assert_eq!(conn.reader.read(&mut [0; 16])?, 0);
+
+    // The shutdown wakes the RESP listener too
+    handle.shutdown();
+    thread::sleep(Duration::from_millis(100));
+    assert!(TcpStream::connect(addr).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,485 @@
+use cykv::*;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server() -> Result<(CyStore, SocketAddr)> {
+    start_server_with(ServerOptions::default())
+}
+
+fn start_server_with(options: ServerOptions) -> Result<(CyStore, SocketAddr)> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(NoCacheManager))?;
+    let server = Server::with_options(store.clone(), "127.0.0.1:0".parse().unwrap(), options)?;
+    let addr = server.local_addr()?;
+    thread::spawn(move || server.run());
+    Ok((store, addr))
+}
+
+struct Connection<'a> {
+    stream: &'a TcpStream,
+    responses: StreamDeserializer<'a, serde_json::de::IoRead<&'a TcpStream>, Response>,
+}
+
+impl<'a> Connection<'a> {
+    fn new(stream: &'a TcpStream) -> Self {
+        Self {
+            stream,
+            responses: Deserializer::from_reader(stream).into_iter(),
+        }
+    }
+
+    fn send(&mut self, req: &Request) -> Result<Response> {
+        serde_json::to_writer(self.stream, req)?;
+        Ok(self.responses.next().unwrap()?)
+    }
+
+    // Collect the pages of a scan
+    fn scan(&mut self, req: &Request) -> Result<Vec<Vec<(String, String)>>> {
+        let mut pages = Vec::new();
+        let mut res = self.send(req)?;
+        loop {
+            match res {
+                Response::Page { Pairs, More } => {
+                    pages.push(Pairs);
+                    if !More {
+                        return Ok(pages);
+                    }
+                }
+                res => panic!("unexpected response {:?}", res),
+            }
+            res = self.responses.next().unwrap()?;
+        }
+    }
+
+    // Collect the pages of a Keys request
+    fn keys(&mut self, req: &Request) -> Result<Vec<Vec<String>>> {
+        let mut pages = Vec::new();
+        let mut res = self.send(req)?;
+        loop {
+            match res {
+                Response::Keys { Keys, More } => {
+                    pages.push(Keys);
+                    if !More {
+                        return Ok(pages);
+                    }
+                }
+                res => panic!("unexpected response {:?}", res),
+            }
+            res = self.responses.next().unwrap()?;
+        }
+    }
+}
+
+#[test]
+fn scan_in_pages() -> Result<()> {
+    let (store, addr) = start_server()?;
+    for i in 0..250 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    let stream = TcpStream::connect(addr)?;
+    let mut conn = Connection::new(&stream);
+
+    let pages = conn.scan(&Request::Range {
+        Start: Unbounded,
+        End: Unbounded,
+        Limit: None,
+        Reverse: false,
+        PageSize: Some(100),
+    })?;
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![100, 100, 50]
+    );
+    assert_eq!(pages[2][49], ("key249".to_owned(), "value249".to_owned()));
+
+    let pages = conn.scan(&Request::Range {
+        Start: Included("key010".to_owned()),
+        End: Excluded("key020".to_owned()),
+        Limit: Some(3),
+        Reverse: true,
+        PageSize: None,
+    })?;
+    let keys: Vec<&str> = pages[0].iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["key019", "key018", "key017"]);
+
+    let pages = conn.scan(&Request::ScanPrefix {
+        Prefix: "key1".to_owned(),
+        Limit: None,
+        Reverse: false,
+        PageSize: Some(10),
+    })?;
+    // The last full page ends the scan
+    assert_eq!(pages.len(), 10);
+    assert_eq!(pages[9].len(), 10);
+    assert_eq!(pages.concat().len(), 100);
+    let pages = conn.scan(&Request::ScanPrefix {
+        Prefix: "missing".to_owned(),
+        Limit: None,
+        Reverse: false,
+        PageSize: Some(10),
+    })?;
+    assert_eq!(pages, vec![Vec::new()]);
+
+    let pages = conn.keys(&Request::Keys {
+        Start: Included("key100".to_owned()),
+        End: Unbounded,
+        Limit: Some(120),
+        Reverse: true,
+        PageSize: Some(40),
+    })?;
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![40, 40, 40]
+    );
+    assert_eq!(pages[0][0], "key249");
+    assert_eq!(pages[2][39], "key130");
+    let pages = conn.keys(&Request::Keys {
+        Start: Unbounded,
+        End: Unbounded,
+        Limit: None,
+        Reverse: false,
+        PageSize: Some(100),
+    })?;
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![100, 100, 50]
+    );
+    assert_eq!(pages[1][0], "key100");
+
+    // The connection is still usable after the pages
+    match conn.send(&Request::Count {
+        Start: Included("key100".to_owned()),
+        End: Unbounded,
+    })? {
+        Response::Count(count) => assert_eq!(count, 150),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn structured_errors() -> Result<()> {
+    let (_store, addr) = start_server()?;
+    let stream = TcpStream::connect(addr)?;
+    let mut conn = Connection::new(&stream);
+
+    match conn.send(&Request::Remove {
+        Key: "missing".to_owned(),
+    })? {
+        Response::Err(err) => {
+            assert_eq!(err.code, ErrorCode::NotFound);
+            assert!(err.message.contains("missing"));
+            assert!(!err.retryable);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // NoCacheManager has no memory budget to resize
+    match conn.send(&Request::Resize {
+        CacheBytes: 1 << 20,
+    })? {
+        Response::Err(err) => assert_eq!(err.code, ErrorCode::Unsupported),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // A malformed request is answered before the connection is closed
+    let stream = TcpStream::connect(addr)?;
+    (&stream).write_all(br#"{"Get":{"Name":"key"}}"#)?;
+    let res: Response = serde_json::from_reader(&stream)?;
+    match res {
+        Response::Err(err) => assert_eq!(err.code, ErrorCode::InvalidRequest),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // The JSON the engine can't read back is its own fault, not the client's
+    let err = CyKvError::from(serde_json::from_str::<u64>("{").unwrap_err());
+    assert_eq!(WireError::from(&err).code, ErrorCode::Corruption);
+
+    let res = serde_json::to_string(&Response::Err(WireError::new(ErrorCode::Io, "disk")))?;
+    assert_eq!(
+        res,
+        r#"{"Err":{"Code":"Io","Message":"disk","Retryable":false}}"#
+    );
+
+    Ok(())
+}
+
+// A frame of the binary protocol: its length, the request id, the opcode and the payload
+fn frame(id: u64, opcode: u8, payload: &[&[u8]]) -> Vec<u8> {
+    let payload = payload.concat();
+    let mut frame = (9 + payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.push(opcode);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+fn read_frame(mut stream: &TcpStream) -> Result<(u64, u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame)?;
+    let id = u64::from_be_bytes(frame[..8].try_into().unwrap());
+    Ok((id, frame[8], frame[9..].to_vec()))
+}
+
+#[test]
+fn binary_protocol() -> Result<()> {
+    let (store, addr) = start_server()?;
+    let mut stream = TcpStream::connect(addr)?;
+
+    // The server answers the preamble with the version it speaks
+    stream.write_all(b"CYKV\x01")?;
+    let mut preamble = [0; 5];
+    stream.read_exact(&mut preamble)?;
+    assert_eq!(&preamble, b"CYKV\x01");
+
+    // The strings are length-prefixed, anything can be in them
+    let key: &[u8] = b"\x00\x00\x00\x03key";
+    let value: &[u8] = b"\x00\x00\x00\x0b{\"a\":\n\"b\"}\x00";
+    stream.write_all(&frame(7, 2, &[key, value]))?;
+    assert_eq!(read_frame(&stream)?, (7, 1, vec![0]));
+    assert_eq!(
+        store.get("key".to_owned())?,
+        Some("{\"a\":\n\"b\"}\0".to_owned())
+    );
+    stream.write_all(&frame(8, 1, &[key]))?;
+    assert_eq!(read_frame(&stream)?, (8, 1, [&[1], value].concat()));
+
+    // A malformed frame is answered with its id, and the next frames are still read
+    stream.write_all(&frame(9, 99, &[]))?;
+    let (id, opcode, payload) = read_frame(&stream)?;
+    assert_eq!((id, opcode, &payload[..2]), (9, 6, &[1, 0][..]));
+    assert!(String::from_utf8_lossy(&payload).contains("unknown opcode 99"));
+    stream.write_all(&frame(10, 7, &[&[0, 0]]))?;
+    assert_eq!(read_frame(&stream)?, (10, 4, 1u64.to_be_bytes().to_vec()));
+
+    // The connection is closed after a frame too large to be read
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    let (id, opcode, _) = read_frame(&stream)?;
+    assert_eq!((id, opcode), (0, 6));
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn pipelined_frames() -> Result<()> {
+    let (store, addr) = start_server()?;
+    let mut stream = TcpStream::connect(addr)?;
+    let key: &[u8] = b"\x00\x00\x00\x03key";
+    let value: &[u8] = b"\x00\x00\x00\x05value";
+
+    // The requests are sent at once, with ids chosen by the client,
+    // and answered in order
+    let mut requests = b"CYKV\x01".to_vec();
+    requests.extend(frame(100, 2, &[key, value]));
+    requests.extend(frame(5, 3, &[b"\x00\x00\x00\x07missing"]));
+    requests.extend(frame(42, 1, &[key]));
+    requests.extend(frame(42, 2, &[key, b"\x00\x00\x00\x00"]));
+    requests.extend(frame(7, 3, &[key]));
+    requests.extend(frame(0, 7, &[&[0, 0]]));
+    stream.write_all(&requests)?;
+
+    let mut preamble = [0; 5];
+    stream.read_exact(&mut preamble)?;
+    assert_eq!(read_frame(&stream)?, (100, 1, vec![0]));
+    let (id, opcode, payload) = read_frame(&stream)?;
+    assert_eq!((id, opcode, payload[0]), (5, 6, 0));
+    assert_eq!(read_frame(&stream)?, (42, 1, [&[1], value].concat()));
+    assert_eq!(read_frame(&stream)?, (42, 1, [&[1], value].concat()));
+    assert_eq!(read_frame(&stream)?, (7, 1, vec![1, 0, 0, 0, 0]));
+    assert_eq!(read_frame(&stream)?, (0, 4, 0u64.to_be_bytes().to_vec()));
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn connection_limit() -> Result<()> {
+    let (_store, addr) = start_server_with(ServerOptions {
+        max_connections: 1,
+        ..ServerOptions::default()
+    })?;
+    let get = Request::Get {
+        Key: "key".to_owned(),
+    };
+
+    let first = TcpStream::connect(addr)?;
+    let mut conn = Connection::new(&first);
+    assert!(matches!(conn.send(&get)?, Response::Ok(None)));
+
+    // The connection over the limit is refused with a retryable error
+    let second = TcpStream::connect(addr)?;
+    match Connection::new(&second).send(&get)? {
+        Response::Err(err) => {
+            assert_eq!(err.code, ErrorCode::Unavailable);
+            assert!(err.retryable);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // The closed connection frees its place
+    drop(conn);
+    drop(first);
+    for _ in 0..100 {
+        let stream = TcpStream::connect(addr)?;
+        if let Response::Ok(None) = Connection::new(&stream).send(&get)? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the connection limit wasn't released");
+}
+
+#[test]
+fn connections_wait_for_a_worker() -> Result<()> {
+    let (_store, addr) = start_server_with(ServerOptions {
+        workers: 1,
+        ..ServerOptions::default()
+    })?;
+    let get = Request::Get {
+        Key: "key".to_owned(),
+    };
+
+    let first = TcpStream::connect(addr)?;
+    let mut conn = Connection::new(&first);
+    assert!(matches!(conn.send(&get)?, Response::Ok(None)));
+
+    // The second connection is queued while the only worker serves the first
+    let second = TcpStream::connect(addr)?;
+    serde_json::to_writer(&second, &get)?;
+    second.set_read_timeout(Some(Duration::from_millis(200)))?;
+    assert!((&second).read(&mut [0; 64]).is_err());
+
+    drop(conn);
+    drop(first);
+    second.set_read_timeout(None)?;
+    let res: Response = Deserializer::from_reader(&second)
+        .into_iter()
+        .next()
+        .unwrap()?;
+    assert!(matches!(res, Response::Ok(None)));
+
+    Ok(())
+}
+
+#[test]
+fn idle_connections_outnumber_workers() -> Result<()> {
+    let (_store, addr) = start_server_with(ServerOptions {
+        workers: 2,
+        queue_timeout: Some(Duration::from_millis(200)),
+        ..ServerOptions::default()
+    })?;
+    let get = Request::Get {
+        Key: "key".to_owned(),
+    };
+
+    // The idle clients keep both workers
+    let idle: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<io::Result<_>>()?;
+    for stream in &idle {
+        assert!(matches!(
+            Connection::new(stream).send(&get)?,
+            Response::Ok(None)
+        ));
+    }
+
+    // The connections queued behind them are refused after the queue timeout,
+    // not after the idle timeout
+    let queued: Vec<TcpStream> = (0..4)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<io::Result<_>>()?;
+    let start = Instant::now();
+    for stream in &queued {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        match Connection::new(stream).send(&get)? {
+            Response::Err(err) => {
+                assert_eq!(err.code, ErrorCode::Unavailable);
+                assert!(err.retryable);
+            }
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // A worker freed by an idle client serves the next connection
+    drop(idle);
+    let stream = TcpStream::connect(addr)?;
+    assert!(matches!(
+        Connection::new(&stream).send(&get)?,
+        Response::Ok(None)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn idle_and_read_timeouts() -> Result<()> {
+    let (_store, addr) = start_server_with(ServerOptions {
+        workers: 1,
+        idle_timeout: Some(Duration::from_millis(100)),
+        read_timeout: Some(Duration::from_millis(100)),
+        ..ServerOptions::default()
+    })?;
+    let get = Request::Get {
+        Key: "key".to_owned(),
+    };
+
+    // An idle connection is closed, and frees the worker
+    let stream = TcpStream::connect(addr)?;
+    assert!(matches!(
+        Connection::new(&stream).send(&get)?,
+        Response::Ok(None)
+    ));
+    assert_eq!((&stream).read(&mut [0; 64])?, 0);
+
+    // So is a connection stuck in the middle of a request
+    let stream = TcpStream::connect(addr)?;
+    (&stream).write_all(br#"{"Get":"#)?;
+    assert!(matches!((&stream).read(&mut [0; 64]), Ok(0) | Err(_)));
+
+    let stream = TcpStream::connect(addr)?;
+    assert!(matches!(
+        Connection::new(&stream).send(&get)?,
+        Response::Ok(None)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = CyStore::open(temp_dir.clone(), Box::new(NoCacheManager))?;
+    let server = Server::new(store, "127.0.0.1:0".parse().unwrap())?;
+    let addr = server.local_addr()?;
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let idle = TcpStream::connect(addr)?;
+    let res = Connection::new(&idle).send(&Request::Set {
+        Key: "key".to_owned(),
+        Value: "value".to_owned(),
+    })?;
+    assert!(matches!(res, Response::Ok(None)));
+
+    // The idle connection doesn't hold the shutdown back
+    handle.shutdown();
+    running.join().unwrap()?;
+    assert_eq!((&idle).read(&mut [0; 64])?, 0);
+    assert!(TcpStream::connect(addr).is_err());
+
+    // The store is synced and its directory unlocked
+    let store = CyStore::open(temp_dir, Box::new(NoCacheManager))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use cykv::*;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+fn server() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_server"))
+}
+
+#[test]
+fn server_flags_and_config_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("cykv.json");
+    fs::write(
+        &config,
+        format!(
+            r#"{{"addr": "127.0.0.1:2958", "data_dir": {:?}, "cache": "lru", "cache_bytes": 1048576}}"#,
+            data_dir
+        ),
+    )?;
+
+    // The flags override the config file, port 0 lets several instances run at once
+    let mut child = server()
+        .arg("--config")
+        .arg(&config)
+        .args([
+            "--addr",
+            "127.0.0.1:0",
+            "--cache-policy=clock",
+            "--write-back",
+        ])
+        .args(["--durability", "periodic", "--sync-interval-ms", "50"])
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut line = String::new();
+    BufReader::new(child.stderr.take().unwrap()).read_line(&mut line)?;
+    let addr = line
+        .split_whitespace()
+        .find_map(|word| {
+            word.trim_end_matches(',')
+                .parse::<std::net::SocketAddr>()
+                .ok()
+        })
+        .unwrap_or_else(|| panic!("no address in {:?}", line));
+    assert!(line.contains(data_dir.to_str().unwrap()));
+
+    let client = Client::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.stats()?.cache.capacity_bytes, 1 << 20);
+    assert!(data_dir.is_dir());
+
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+#[test]
+fn server_invalid_settings() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "")?;
+
+    // All the problems are reported at once
+    let output = server()
+        .args(["--addr", "127.0.0.1:http", "--data-dir"])
+        .arg(&file)
+        .args([
+            "--cache",
+            "mmap",
+            "--cache-bytes",
+            "64M",
+            "--durability",
+            "always",
+        ])
+        .args(["--max-connections", "0", "--log-level", "loud"])
+        .args(["--resp-addr", "localhost"])
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for problem in &[
+        "invalid addr 127.0.0.1:http",
+        "isn't a directory",
+        "cache_bytes needs the lru cache",
+        "invalid durability always",
+        "max_connections must be positive",
+        "invalid log_level loud",
+        "invalid resp_addr localhost",
+    ] {
+        assert!(stderr.contains(problem), "{:?} not in {}", problem, stderr);
+    }
+
+    // The data_dir isn't created for invalid settings
+    let data_dir = temp_dir.path().join("data");
+    let output = server()
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .args(["--workers", "0"])
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("workers must be positive"));
+    assert!(!data_dir.exists());
+
+    let output = server().args(["--cache-bytes", "lots"]).output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid value lots of --cache-bytes"));
+
+    // The unknown settings of the config file are errors too
+    let config = temp_dir.path().join("cykv.json");
+    fs::write(&config, r#"{"adress": "127.0.0.1:2958"}"#)?;
+    let output = server().arg("--config").arg(&config).output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown field `adress`"));
+
+    Ok(())
+}
+
+#[test]
+fn server_stops_on_sigterm() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let data_dir = temp_dir.path().to_str().unwrap();
+    let mut child = server()
+        .args(["--addr", "127.0.0.1:0", "--data-dir", data_dir])
+        .args(["--cache", "lru", "--write-back"])
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line)?;
+    let addr = line
+        .split_whitespace()
+        .find_map(|word| {
+            word.trim_end_matches(',')
+                .parse::<std::net::SocketAddr>()
+                .ok()
+        })
+        .unwrap_or_else(|| panic!("no address in {:?}", line));
+
+    let client = Client::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    // Another server can't use the same directory
+    let output = server()
+        .args(["--addr", "127.0.0.1:0", "--data-dir", data_dir])
+        .output()?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is locked by another store"));
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()?;
+    assert!(status.success());
+    assert!(child.wait()?.success());
+    let mut rest = String::new();
+    stderr.read_to_string(&mut rest)?;
+    assert!(rest.contains("shut down"), "{}", rest);
+
+    // The dirty chunks of the write-back cache were written
+    let store = CyStore::open(temp_dir.path().to_owned(), Box::new(NoCacheManager))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
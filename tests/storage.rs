@@ -1,18 +1,27 @@
 use cykv::*;
+use std::fs;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::path::PathBuf;
-use std::sync::{Arc, Barrier, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 fn no_cache_storage(path: PathBuf) -> Result<CyStore> {
-    let cache_manager = Box::new(NoCacheManager{});
+    let cache_manager = Box::new(NoCacheManager {});
+    CyStore::open(path, cache_manager)
+}
+
+fn mmap_cache_storage(path: PathBuf) -> Result<CyStore> {
+    let cache_manager = Box::new(MmapCacheManager::new());
     CyStore::open(path, cache_manager)
 }
 
 #[test]
 fn get_stored_value() -> Result<()> {
-    let dir = TempDir::new()?.into_path();
+    let dir = TempDir::new()?.keep();
     let store = no_cache_storage(dir.clone())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
@@ -32,7 +41,7 @@ fn get_stored_value() -> Result<()> {
 
 #[test]
 fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir.clone())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
@@ -52,7 +61,7 @@ fn overwrite_value() -> Result<()> {
 
 #[test]
 fn get_non_existent_value() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir.clone())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
@@ -68,7 +77,7 @@ fn get_non_existent_value() -> Result<()> {
 
 #[test]
 fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir)?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
@@ -76,7 +85,7 @@ fn remove_non_existent_key() -> Result<()> {
 
 #[test]
 fn remove_key() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir)?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
@@ -84,11 +93,960 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn set_and_remove_return_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir.clone())?;
+
+    assert_eq!(store.set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = no_cache_storage(temp_dir)?;
+    assert_eq!(
+        store.set("key1".to_owned(), "value3".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.remove("key1".to_owned())?, "value3".to_owned());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.set("key1".to_owned(), "value4".to_owned())?, None);
+
+    Ok(())
+}
+
+// A write whose previous value can't be read back fails, and leaves the store unchanged
+#[test]
+fn unreadable_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Corrupt the first command, key1 is the first element of its document
+    let log = fs::read_dir(&temp_dir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    let original = fs::read(&log)?;
+    let mut corrupted = original.clone();
+    corrupted[4..8].copy_from_slice(&[0xff; 4]);
+    fs::write(&log, &corrupted)?;
+
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.set("key1".to_owned(), "other".to_owned()).is_err());
+    assert_eq!(store.count(..)?, 2);
+
+    // Nothing reached the log
+    assert_eq!(fs::read(&log)?, corrupted);
+    fs::write(&log, &original)?;
+    drop(store);
+    let store = no_cache_storage(temp_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.remove("key1".to_owned())?, "value1");
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn range_with_bounds_limit_and_reverse() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir)?;
+    for i in 0..500 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+
+    let pairs: Vec<(String, String)> = store
+        .range(
+            "key100".to_owned()..="key299".to_owned(),
+            ScanOptions::default(),
+        )
+        .collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 200);
+    assert_eq!(pairs[0], ("key100".to_owned(), "value100".to_owned()));
+    assert_eq!(pairs[199], ("key299".to_owned(), "value299".to_owned()));
+
+    let keys: Vec<String> = store
+        .range(
+            (Excluded("key100".to_owned()), Unbounded),
+            ScanOptions::default().reverse().limit(3),
+        )
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key499", "key498", "key497"]);
+
+    let keys: Vec<String> = store
+        .range(
+            (Unbounded, Excluded("key002".to_owned())),
+            ScanOptions::default().reverse(),
+        )
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key001", "key000"]);
+
+    assert_eq!(
+        store
+            .range(
+                (Included("key2".to_owned()), Excluded("key1".to_owned())),
+                ScanOptions::default()
+            )
+            .count(),
+        0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn range_does_not_block_writers() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir)?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+    }
+
+    // Writes between batches neither deadlock nor show up behind the cursor
+    let mut count = 0;
+    for pair in store.range(.., ScanOptions::default()) {
+        let (key, value) = pair?;
+        assert_eq!(
+            key.trim_start_matches("key"),
+            value.trim_start_matches("value")
+        );
+        store.set(format!("a{}", key), value)?;
+        count += 1;
+    }
+    assert_eq!(count, 1000);
+
+    Ok(())
+}
+
+#[test]
+fn prefix_scan_keys_and_count() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir)?;
+    for user in 40..44 {
+        for item in 0..10 {
+            store.set(format!("user:{}:{}", user, item), format!("{}", item))?;
+        }
+    }
+    store.set("user:42".to_owned(), "no separator".to_owned())?;
+
+    let pairs: Vec<(String, String)> = store
+        .scan_prefix("user:42:".to_owned(), ScanOptions::default())
+        .collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 10);
+    assert_eq!(pairs[0], ("user:42:0".to_owned(), "0".to_owned()));
+    assert_eq!(pairs[9], ("user:42:9".to_owned(), "9".to_owned()));
+
+    let values: Vec<String> = store
+        .scan_prefix(
+            "user:43".to_owned(),
+            ScanOptions::default().reverse().limit(2),
+        )
+        .map(|pair| pair.map(|(_, value)| value))
+        .collect::<Result<_>>()?;
+    assert_eq!(values, vec!["9", "8"]);
+    assert_eq!(
+        store
+            .scan_prefix("user:5".to_owned(), ScanOptions::default())
+            .count(),
+        0
+    );
+
+    let keys = store.keys(
+        "user:41:8".to_owned()..="user:42:1".to_owned(),
+        ScanOptions::default(),
+    )?;
+    assert_eq!(
+        keys,
+        vec![
+            "user:41:8",
+            "user:41:9",
+            "user:42",
+            "user:42:0",
+            "user:42:1"
+        ]
+    );
+    assert_eq!(
+        store.keys(.., ScanOptions::default().limit(1))?,
+        vec!["user:40:0"]
+    );
+
+    assert_eq!(store.count(..)?, 41);
+    assert_eq!(
+        store.count("user:42:".to_owned().."user:42;".to_owned())?,
+        10
+    );
+    assert_eq!(store.count("user:9".to_owned().."user:0".to_owned())?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir.clone())?;
+    for tenant in 0..3 {
+        for i in 0..100 {
+            store.set(format!("tenant{}:{:03}", tenant, i), format!("{}", i))?;
+        }
+    }
+
+    assert_eq!(
+        store.delete_range("tenant1:".to_owned().."tenant2:".to_owned())?,
+        100
+    );
+    assert_eq!(
+        store.delete_range("tenant1:".to_owned().."tenant2:".to_owned())?,
+        0
+    );
+    assert_eq!(store.get("tenant1:000".to_owned())?, None);
+    assert_eq!(store.count(..)?, 200);
+
+    // Keys written after the tombstone survive the replay
+    store.set("tenant1:050".to_owned(), "new".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = no_cache_storage(temp_dir)?;
+    assert_eq!(store.count(..)?, 201);
+    assert_eq!(store.get("tenant0:099".to_owned())?, Some("99".to_owned()));
+    assert_eq!(store.get("tenant1:049".to_owned())?, None);
+    assert_eq!(store.get("tenant1:050".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("tenant2:000".to_owned())?, Some("0".to_owned()));
+
+    assert_eq!(store.delete_range(..)?, 201);
+    assert_eq!(store.count(..)?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn mmap_cache_reads_growing_log() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = mmap_cache_storage(temp_dir.clone())?;
+
+    // Each get reads the command just written
+    for i in 0..200 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+        assert_eq!(
+            store.get(format!("key{:03}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(
+        store.set("key000".to_owned(), "new".to_owned())?,
+        Some("value0".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = mmap_cache_storage(temp_dir)?;
+    assert_eq!(store.get("key000".to_owned())?, Some("new".to_owned()));
+    let values: Vec<String> = store
+        .range(
+            "key100".to_owned()..="key199".to_owned(),
+            ScanOptions::default(),
+        )
+        .map(|pair| pair.map(|(_, value)| value))
+        .collect::<Result<_>>()?;
+    assert_eq!(values.len(), 100);
+    assert_eq!(values[99], "value199");
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..200 {
+                let key_id = (i + thread_id * 25) % 200;
+                if key_id == 0 {
+                    continue;
+                }
+                assert_eq!(
+                    store.get(format!("key{:03}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// The writes reach the log at once, the reads of the last ones are served from memory,
+// or a new mapping once 64 KiB more are written
+#[test]
+fn mmap_cache_keeps_written_tail() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = mmap_cache_storage(temp_dir.clone())?;
+    let mut written = 0;
+    for i in 0..5000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+        assert!(log_bytes(&temp_dir) > written);
+        written = log_bytes(&temp_dir);
+        assert_eq!(
+            store.get(format!("key{:04}", i / 2))?,
+            Some(format!("value{:04}", i / 2))
+        );
+    }
+    // Only the full tails are mapped again
+    let stats = store.stats()?;
+    assert!(stats.cache.misses < 10, "{} remaps", stats.cache.misses);
+    assert!(stats.cache.resident_bytes <= written);
+
+    // A crash of the process loses none of the writes
+    let store = no_cache_storage(crash_copy(&temp_dir)?)?;
+    assert_eq!(store.count(..)?, 5000);
+    assert_eq!(
+        store.get("key4999".to_owned())?,
+        Some("value4999".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn value_cache_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let options = StoreOptions {
+        value_cache_bytes: 1 << 10,
+        ..StoreOptions::default()
+    };
+    let store =
+        CyStore::open_with_options(temp_dir.clone(), Box::new(NoCacheManager), options.clone())?;
+
+    // The budget holds only a few values, the rest are evicted
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    for _ in 0..2 {
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    // Cached values are dropped when the keys change
+    assert_eq!(
+        store.set("key1".to_owned(), "new".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.remove("key1".to_owned())?, "new".to_owned());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(
+        store.delete_range("key9".to_owned().."key:".to_owned())?,
+        11
+    );
+    assert_eq!(store.get("key99".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store =
+        CyStore::open_with_options(temp_dir.clone(), Box::new(NoCacheManager), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, None);
+
+    // Compaction moves the values to reused ids and positions,
+    // the values cached at the old ones must not be served.
+    // The hot keys change the length of their values, so the cold keys move
+    drop(store);
+    let options = StoreOptions {
+        value_cache_bytes: 64 << 10,
+        compact_threshold: 2 << 10,
+        ..options
+    };
+    let store = CyStore::open_with_options(temp_dir, Box::new(NoCacheManager), options)?;
+    let mut compactions = 0;
+    let mut uncompacted = store.stats()?.uncompacted_bytes;
+    for round in 0..20 {
+        let value = |i| format!("{}{}", "x".repeat(round % 5), i);
+        for i in 2..50 {
+            store.set(format!("key{}", i), value(i))?;
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+            let stats = store.stats()?;
+            if stats.uncompacted_bytes < uncompacted {
+                compactions += 1;
+            }
+            uncompacted = stats.uncompacted_bytes;
+        }
+        for i in 2..90 {
+            let expected = if i < 50 {
+                value(i)
+            } else {
+                format!("value{}", i)
+            };
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+        }
+    }
+    assert!(compactions >= 2, "{} compactions", compactions);
+
+    Ok(())
+}
+
+// Overwrite the keys until several compactions have replaced and reused the log ids,
+// and check that no stale cached data is served
+fn reads_across_compaction(open: impl Fn(PathBuf, StoreOptions) -> Result<CyStore>) -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let options = StoreOptions {
+        value_cache_bytes: 4 << 10,
+        compact_threshold: 16 << 10,
+        ..StoreOptions::default()
+    };
+
+    for round in 0..3 {
+        let store = open(temp_dir.clone(), options.clone())?;
+        for iter in 0..20 {
+            for key_id in 0..100 {
+                let key = format!("key{:03}", key_id);
+                let value = format!("{}-{}-{}", round, iter, key_id);
+                store.set(key.clone(), value.clone())?;
+                assert_eq!(store.get(key)?, Some(value));
+            }
+            for key_id in 0..100 {
+                let key = format!("key{:03}", key_id);
+                let value = format!("{}-{}-{}", round, iter, key_id);
+                assert_eq!(store.get(key)?, Some(value));
+            }
+            let values: Vec<String> = store
+                .range(.., ScanOptions::default())
+                .map(|pair| pair.map(|(_, value)| value))
+                .collect::<Result<_>>()?;
+            assert_eq!(values.len(), 100);
+            assert_eq!(values[99], format!("{}-{}-99", round, iter));
+        }
+    }
+
+    // Open from disk again and check persistent data
+    let store = open(temp_dir, options)?;
+    for key_id in 0..100 {
+        let key = format!("key{:03}", key_id);
+        assert_eq!(store.get(key)?, Some(format!("2-19-{}", key_id)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn lru_cache_reads_across_compaction() -> Result<()> {
+    reads_across_compaction(|path, options| {
+        CyStore::open_with_options(path, Box::new(LruCacheManager::new(64 << 10)), options)
+    })
+}
+
+#[test]
+fn chunk_cache_policies_across_compaction() -> Result<()> {
+    for policy in [
+        EvictionPolicyKind::Clock,
+        EvictionPolicyKind::SegmentedLru,
+        EvictionPolicyKind::TinyLfu,
+    ] {
+        reads_across_compaction(|path, options| {
+            let cache_manager = LruCacheManager::with_policy(64 << 10, policy);
+            CyStore::open_with_options(path, Box::new(cache_manager), options)
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn chunk_sizes_across_compaction() -> Result<()> {
+    for (chunk_size, max_readahead) in [(1 << 10, 0), (1 << 10, 8), (16 << 10, 4)] {
+        reads_across_compaction(|path, options| {
+            let cache_manager = LruCacheManager::with_options(LruCacheOptions {
+                cache_bytes: 64 << 10,
+                chunk_size,
+                max_readahead,
+                ..LruCacheOptions::default()
+            });
+            CyStore::open_with_options(path, Box::new(cache_manager), options)
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn cache_stats() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let options = StoreOptions {
+        value_cache_bytes: 4 << 10,
+        ..StoreOptions::default()
+    };
+    let cache_manager = Box::new(LruCacheManager::new(4 << 12));
+    let store = CyStore::open_with_options(temp_dir, cache_manager, options)?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+    }
+    store.remove("key0000".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 999);
+    assert!(stats.uncompacted_bytes > 0);
+    assert!(stats.cache.resident_chunks <= 4);
+    assert!(stats.cache.evictions > 0);
+
+    let (cache, value_cache) = (stats.cache, stats.value_cache.unwrap());
+    for _ in 0..2 {
+        for i in (1..1000).step_by(100) {
+            store.get(format!("key{:04}", i))?;
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats.cache.misses > cache.misses);
+    assert!(stats.cache.bytes_loaded > cache.bytes_loaded);
+    let value_cache_stats = stats.value_cache.unwrap();
+    assert_eq!(value_cache_stats.misses - value_cache.misses, 10);
+    assert_eq!(value_cache_stats.hits - value_cache.hits, 10);
+    assert_eq!(value_cache_stats.entries, 10);
+
+    // Scans bypass the value cache, the second scan hits the loaded chunks
+    store.range(.., ScanOptions::default().limit(10)).count();
+    let cache = store.stats()?.cache;
+    store.range(.., ScanOptions::default().limit(10)).count();
+    let stats = store.stats()?;
+    assert!(stats.cache.hits > cache.hits);
+    assert_eq!(stats.cache.misses, cache.misses);
+
+    let temp_dir = TempDir::new()?.keep();
+    let store = mmap_cache_storage(temp_dir.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    // Served from the kept tail of the active log
+    store.get("key".to_owned())?;
+    assert_eq!(store.stats()?.cache.misses, 0);
+    drop(store);
+    let store = mmap_cache_storage(temp_dir)?;
+    store.get("key".to_owned())?;
+    store.get("key".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.cache.misses, 1);
+    assert!(stats.cache.hits >= 2);
+    assert_eq!(stats.cache.resident_chunks, 1);
+    assert_eq!(stats.value_cache, None);
+
+    Ok(())
+}
+
+#[test]
+fn resize_cache() -> Result<()> {
+    for policy in [
+        EvictionPolicyKind::Lru,
+        EvictionPolicyKind::Clock,
+        EvictionPolicyKind::SegmentedLru,
+        EvictionPolicyKind::TinyLfu,
+    ] {
+        let temp_dir = TempDir::new()?.keep();
+        let cache_manager = LruCacheManager::with_policy(64 << 12, policy).write_back(None);
+        let store = CyStore::open(temp_dir.clone(), Box::new(cache_manager))?;
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+        }
+        let stats = store.stats()?.cache;
+        assert_eq!(stats.capacity_bytes, 64 << 12);
+        assert!(stats.resident_bytes <= stats.capacity_bytes);
+
+        // Shrinking evicts and writes back the chunks beyond the budget
+        store.resize_cache(4 << 12)?;
+        let stats = store.stats()?.cache;
+        assert_eq!(stats.capacity_bytes, 4 << 12);
+        assert!(stats.resident_chunks <= 4);
+        assert!(stats.resident_bytes <= 4 << 12);
+        for i in (0..1000).step_by(7) {
+            assert_eq!(
+                store.get(format!("key{:04}", i))?,
+                Some(format!("value{:04}", i))
+            );
+            assert!(store.stats()?.cache.resident_bytes <= 4 << 12);
+        }
+
+        store.resize_cache(128 << 12)?;
+        let values: Vec<String> = store
+            .range(.., ScanOptions::default())
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 1000);
+        let stats = store.stats()?.cache;
+        assert_eq!(stats.capacity_bytes, 128 << 12);
+        assert!(stats.resident_chunks > 4);
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = no_cache_storage(temp_dir)?;
+        assert_eq!(
+            store.get("key0999".to_owned())?,
+            Some("value0999".to_owned())
+        );
+        assert!(store.resize_cache(1 << 20).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cache_peak_within_budget() -> Result<()> {
+    let chunk_size = 1 << 10;
+    let max_readahead = 4;
+    for policy in [EvictionPolicyKind::Lru, EvictionPolicyKind::TinyLfu] {
+        let temp_dir = TempDir::new()?.keep();
+        // Fewer chunks than shards
+        let cache_manager = LruCacheManager::with_options(LruCacheOptions {
+            cache_bytes: 6 * chunk_size as u64,
+            policy,
+            shards: 16,
+            chunk_size,
+            max_readahead,
+        })
+        .write_back(None);
+        let store = CyStore::open(temp_dir.clone(), Box::new(cache_manager))?;
+        let slack = ((1 + max_readahead) * chunk_size) as u64;
+        let check = |capacity: u64| -> Result<()> {
+            let stats = store.stats()?.cache;
+            assert!(stats.resident_bytes <= capacity);
+            assert!(
+                stats.peak_resident_bytes <= 6 * chunk_size as u64 + slack,
+                "peak {} with {:?}",
+                stats.peak_resident_bytes,
+                policy
+            );
+            Ok(())
+        };
+
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+        }
+        check(6 * chunk_size as u64)?;
+        for i in (0..1000).step_by(7) {
+            store.get(format!("key{:04}", i))?;
+        }
+        assert_eq!(store.range(.., ScanOptions::default()).count(), 1000);
+        check(6 * chunk_size as u64)?;
+        if let EvictionPolicyKind::Lru = policy {
+            // Every chunk of the budget is used, though each shard gets less than one
+            assert_eq!(store.stats()?.cache.resident_chunks, 6);
+        }
+
+        store.resize_cache(3 * chunk_size as u64)?;
+        for i in (0..1000).step_by(3) {
+            store.set(format!("key{:04}", i), format!("new{:04}", i))?;
+        }
+        assert_eq!(store.range(.., ScanOptions::default()).count(), 1000);
+        check(3 * chunk_size as u64)?;
+        if let EvictionPolicyKind::Lru = policy {
+            assert_eq!(store.stats()?.cache.resident_chunks, 3);
+        }
+
+        drop(store);
+        let store = no_cache_storage(temp_dir)?;
+        assert_eq!(store.get("key0999".to_owned())?, Some("new0999".to_owned()));
+        assert_eq!(
+            store.get("key0998".to_owned())?,
+            Some("value0998".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sequential_readahead() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+    }
+    drop(store);
+
+    let mut misses = Vec::new();
+    for max_readahead in [0, 16] {
+        let cache_manager = LruCacheManager::with_options(LruCacheOptions {
+            cache_bytes: 1 << 20,
+            chunk_size: 1 << 10,
+            max_readahead,
+            ..LruCacheOptions::default()
+        });
+        let store = CyStore::open(temp_dir.clone(), Box::new(cache_manager))?;
+        let values: Vec<String> = store
+            .range(.., ScanOptions::default())
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 1000);
+        assert_eq!(values[999], "value0999");
+
+        misses.push(store.stats()?.cache.misses);
+    }
+    // The scan reads the log sequentially, the readahead loads it with a few reads
+    assert!(misses[0] > 30);
+    assert!(misses[1] * 4 < misses[0]);
+
+    Ok(())
+}
+
+#[test]
+fn write_back_cache_reads_across_compaction() -> Result<()> {
+    for policy in [EvictionPolicyKind::Lru, EvictionPolicyKind::TinyLfu] {
+        reads_across_compaction(|path, options| {
+            let cache_manager = LruCacheManager::with_policy(64 << 10, policy).write_back(None);
+            CyStore::open_with_options(path, Box::new(cache_manager), options)
+        })?;
+    }
+    Ok(())
+}
+
+// The readers evict the chunks the writer is writing to,
+// every acknowledged value must be read back
+fn writes_with_evictions(cache_manager: LruCacheManager) -> Result<()> {
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(cache_manager))?;
+    let written = Arc::new(AtomicUsize::new(0));
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let written = Arc::clone(&written);
+        handles.push(thread::spawn(move || {
+            let mut seed = thread_id as usize + 1;
+            loop {
+                let done = written.load(Ordering::Acquire);
+                if done == 2000 {
+                    break;
+                }
+                if done == 0 {
+                    continue;
+                }
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let i = (seed >> 33) % done;
+                assert_eq!(
+                    store.get(format!("key{:04}", i)).unwrap(),
+                    Some(format!("value{:04}", i))
+                );
+                thread::yield_now();
+            }
+        }));
+    }
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+        written.store(i + 1, Ordering::Release);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{:04}", i))?,
+            Some(format!("value{:04}", i))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn write_through_cache_concurrent_evictions() -> Result<()> {
+    for policy in [EvictionPolicyKind::Lru, EvictionPolicyKind::TinyLfu] {
+        writes_with_evictions(LruCacheManager::with_shards(8 << 10, policy, 1))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn write_back_cache_concurrent_evictions() -> Result<()> {
+    for policy in [EvictionPolicyKind::Lru, EvictionPolicyKind::TinyLfu] {
+        writes_with_evictions(LruCacheManager::with_shards(8 << 10, policy, 1).write_back(None))?;
+    }
+    Ok(())
+}
+
+fn log_bytes(dir: &PathBuf) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// The files a crash of the store would leave, the store itself is leaked with its lock
+fn crash_copy(dir: &PathBuf) -> Result<PathBuf> {
+    let copy = TempDir::new()?.keep();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() != "LOCK" {
+            fs::copy(entry.path(), copy.join(entry.file_name()))?;
+        }
+    }
+    Ok(copy)
+}
+
+#[test]
+fn write_back_cache_sync() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let cache_manager = LruCacheManager::new(64 << 10).write_back(None);
+    let store = CyStore::open(temp_dir.clone(), Box::new(cache_manager))?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    assert_eq!(log_bytes(&temp_dir), 0);
+    assert_eq!(store.get("key042".to_owned())?, Some("value42".to_owned()));
+
+    store.sync()?;
+    let synced = log_bytes(&temp_dir);
+    assert!(synced > 0);
+    assert!(store.stats()?.cache.write_backs > 0);
+
+    // Crash without flushing the later writes, the synced ones survive
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "lost".to_owned())?;
+    }
+    std::mem::forget(store);
+    let store = no_cache_storage(crash_copy(&temp_dir)?)?;
+    assert_eq!(store.count(..)?, 100);
+    assert_eq!(store.get("key042".to_owned())?, Some("value42".to_owned()));
+    drop(store);
+
+    // Evictions and the periodic flusher write back the chunks in order
+    let temp_dir = TempDir::new()?.keep();
+    let cache_manager = LruCacheManager::new(4 << 12).write_back(Some(Duration::from_millis(10)));
+    let store = CyStore::open(temp_dir.clone(), Box::new(cache_manager))?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    assert!(log_bytes(&temp_dir) > 0);
+    thread::sleep(Duration::from_millis(200));
+    std::mem::forget(store);
+    let store = no_cache_storage(crash_copy(&temp_dir)?)?;
+    assert_eq!(store.count(..)?, 1000);
+    assert_eq!(
+        store.get("key0999".to_owned())?,
+        Some("value999".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn durability() -> Result<()> {
+    // The write-back cache keeps the writes in memory until they are synced
+    let open = |dir: &PathBuf, durability| {
+        let cache_manager = LruCacheManager::new(64 << 10).write_back(None);
+        let options = StoreOptions {
+            durability,
+            ..StoreOptions::default()
+        };
+        CyStore::open_with_options(dir.clone(), Box::new(cache_manager), options)
+    };
+
+    let temp_dir = TempDir::new()?.keep();
+    let store = open(&temp_dir, Durability::Sync)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let synced = log_bytes(&temp_dir);
+    assert!(synced > 0);
+    store.remove("key1".to_owned())?;
+    assert!(log_bytes(&temp_dir) > synced);
+    std::mem::forget(store);
+
+    let temp_dir = TempDir::new()?.keep();
+    let store = open(&temp_dir, Durability::Periodic(Duration::from_millis(10)))?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    std::mem::forget(store);
+    let store = no_cache_storage(crash_copy(&temp_dir)?)?;
+    assert_eq!(store.count(..)?, 100);
+
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let cache_manager = LruCacheManager::new(64 << 10).write_back(None);
+    let options = StoreOptions {
+        durability: Durability::Sync,
+        ..StoreOptions::default()
+    };
+    let temp_dir = TempDir::new()?.keep();
+    let store = CyStore::open_with_options(temp_dir.clone(), Box::new(cache_manager), options)?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+
+    let mut batch: Vec<_> = (0..100)
+        .map(|i| WriteOp::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    batch.push(WriteOp::Remove {
+        key: "missing".to_owned(),
+    });
+    batch.push(WriteOp::Remove {
+        key: "key1".to_owned(),
+    });
+    let BatchResults { results, sync } = store.write_batch(batch)?;
+    assert!(sync.is_ok());
+
+    // The writes are applied in order, and a failed one doesn't stop the others
+    assert_eq!(results.len(), 102);
+    assert_eq!(results[0].as_ref().unwrap(), &Some("old".to_owned()));
+    assert_eq!(results[1].as_ref().unwrap(), &None);
+    assert!(matches!(results[100], Err(CyKvError::KeyNotFound(_))));
+    assert_eq!(results[101].as_ref().unwrap(), &Some("value1".to_owned()));
+
+    // The batch is synced at the end
+    std::mem::forget(store);
+    let store = no_cache_storage(crash_copy(&temp_dir)?)?;
+    assert_eq!(store.count(..)?, 99);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let store = no_cache_storage(temp_dir.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // The clones share the lock, another store can't open the directory
+    let clone = store.clone();
+    drop(store);
+    match no_cache_storage(temp_dir.clone()) {
+        Err(CyKvError::Locked(_)) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+
+    drop(clone);
+    let store = no_cache_storage(temp_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn mmap_cache_reads_across_compaction() -> Result<()> {
+    reads_across_compaction(|path, options| {
+        CyStore::open_with_options(path, Box::new(MmapCacheManager::new()), options)
+    })
+}
+
+#[test]
+fn no_cache_reads_across_compaction() -> Result<()> {
+    reads_across_compaction(|path, options| {
+        CyStore::open_with_options(path, Box::new(NoCacheManager), options)
+    })
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.clone()).into_iter();
@@ -132,9 +1090,55 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// The compaction output is sorted by key,
+// a scan over it reads the neighbouring commands together
+#[test]
+fn compacted_scan_reads() -> Result<()> {
+    let temp_dir = TempDir::new()?.keep();
+    let options = StoreOptions {
+        compact_threshold: 16 << 10,
+        ..StoreOptions::default()
+    };
+    let cache_manager = Box::new(LruCacheManager::new(1 << 20));
+    let store = CyStore::open_with_options(temp_dir, cache_manager, options)?;
+    // Written in reverse, the scans can't read the log in order before the compaction
+    for i in (0..2000).rev() {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+    }
+    let mut uncompacted = store.stats()?.uncompacted_bytes;
+    loop {
+        store.set("pad".to_owned(), "value".to_owned())?;
+        let stats = store.stats()?;
+        if stats.uncompacted_bytes < uncompacted {
+            break;
+        }
+        uncompacted = stats.uncompacted_bytes;
+    }
+
+    let reads = || -> Result<u64> {
+        let cache = store.stats()?.cache;
+        Ok(cache.hits + cache.misses)
+    };
+    for options in [ScanOptions::default(), ScanOptions::default().reverse()] {
+        let before = reads()?;
+        let mut count = 0;
+        for pair in store.range("key".to_owned().."key:".to_owned(), options) {
+            let (key, value) = pair?;
+            assert_eq!(value, key.replace("key", "value"));
+            count += 1;
+        }
+        assert_eq!(count, 2000);
+        // A read per command would touch a chunk per key
+        let reads = reads()? - before;
+        assert!(reads < 200, "{} chunk reads", reads);
+    }
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir.clone())?;
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
@@ -165,7 +1169,7 @@ fn concurrent_set() -> Result<()> {
 
 #[test]
 fn concurrent_get() -> Result<()> {
-    let temp_dir = TempDir::new()?.into_path();
+    let temp_dir = TempDir::new()?.keep();
     let store = no_cache_storage(temp_dir.clone())?;
     for i in 0..100 {
         store
@@ -214,3 +1218,51 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// The gets of different chunks take the locks of different shards,
+// so on a machine with enough cores the threads don't wait for each other
+#[test]
+fn sharded_cache_concurrent_get() -> Result<()> {
+    let cache_manager =
+        LruCacheManager::with_shards(1 << 20, EvictionPolicyKind::Lru, DEFAULT_CHUNK_SHARDS);
+    let store = CyStore::open(TempDir::new()?.keep(), Box::new(cache_manager))?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{:04}", i))?;
+    }
+
+    // The time taken by the threads to do the same number of gets each
+    let run = |threads: usize| {
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|thread_id| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..20000 {
+                        let key_id = (i * 7 + thread_id * 131) % 1000;
+                        assert_eq!(
+                            store.get(format!("key{:04}", key_id)).unwrap(),
+                            Some(format!("value{:04}", key_id))
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        start.elapsed()
+    };
+    run(1);
+    let single = run(1);
+    let parallel = run(4);
+    if thread::available_parallelism()?.get() >= 4 {
+        assert!(
+            parallel < single * 3,
+            "4 threads took {:?}, 1 thread {:?}",
+            parallel,
+            single
+        );
+    }
+
+    Ok(())
+}